@@ -0,0 +1,183 @@
+use cpal::{
+  BuildStreamError, Device, FromSample, I24, SampleFormat, SampleRate, SizedSample, Stream, StreamConfig,
+  traits::DeviceTrait,
+};
+use ringbuf::{HeapProd, traits::{Observer, Producer}};
+
+use std::sync::Arc;
+
+use crate::{
+  DEFAULT_SR,
+  audio::{
+    devices::ChannelNeed,
+    mixer::{DriftControl, MAX_DRIFT},
+    resample::Resampler,
+    routing::ChannelMap,
+  },
+};
+
+/// Sample formats in order of preference, when a device supports several.
+const FORMAT_PREFERENCE: [SampleFormat; 11] = [
+  SampleFormat::F32,
+  SampleFormat::F64,
+  SampleFormat::I32,
+  SampleFormat::I24,
+  SampleFormat::I16,
+  SampleFormat::I64,
+  SampleFormat::U32,
+  SampleFormat::U16,
+  SampleFormat::U64,
+  SampleFormat::I8,
+  SampleFormat::U8,
+];
+
+fn format_rank(format: SampleFormat) -> usize {
+  FORMAT_PREFERENCE
+    .iter()
+    .position(|f| *f == format)
+    .unwrap_or(FORMAT_PREFERENCE.len())
+}
+
+/// Config the device is opened with, in the device's own samplerate and sample format.
+pub struct CaptureConfig {
+  pub stream: StreamConfig,
+  pub format: SampleFormat,
+}
+
+impl CaptureConfig {
+  pub fn sample_rate(&self) -> u32 { self.stream.sample_rate.0 }
+  pub fn needs_resampling(&self) -> bool { self.sample_rate() != DEFAULT_SR as u32 }
+}
+
+/// Picks the config to open the device with. The samplerate is [`DEFAULT_SR`] if the device supports it,
+/// otherwise the supported rate closest to it, which is then resampled in the capture path.
+/// Among equally good rates the sample format closest to f32 is chosen. With routes that leave
+/// the device's channel count open, the fewest channels that cover them go before all of that.
+pub fn input_config(device: &Device, need: ChannelNeed) -> CaptureConfig {
+  let target = DEFAULT_SR as u32;
+  let (channels, sample_rate, format) = device
+    .supported_input_configs()
+    .ok()
+    .and_then(|configs| {
+      configs
+        .filter(|c| need.accepts(c.channels() as usize))
+        .map(|c| (
+          c.channels() as usize,
+          target.clamp(c.min_sample_rate().0, c.max_sample_rate().0),
+          c.sample_format(),
+        ))
+        .min_by_key(|(channels, rate, format)| (*channels, rate.abs_diff(target), format_rank(*format)))
+    })
+    .unwrap_or((need.channels, target, SampleFormat::F32));
+
+  CaptureConfig {
+    stream: StreamConfig {
+      channels: channels as u16,
+      sample_rate: SampleRate(sample_rate),
+      buffer_size: cpal::BufferSize::Default,
+    },
+    format,
+  }
+}
+
+/// Runs inside the cpal callback: converts device samples to f32, routes device
+/// inputs to stream channels, resamples if needed and pushes the result into the ringbuffer.
+pub struct Capture {
+  tx: HeapProd<f32>,
+  channel_map: Option<ChannelMap>,
+  resampler: Option<Resampler>,
+  drift: Option<Arc<DriftControl>>,
+  gain: f32,
+  scratch: Vec<f32>,
+  routed: Vec<f32>,
+}
+
+impl Capture {
+  /// `channels` is the channel count of the stream, after routing.
+  /// Devices mixed with another device's clock pass a `drift` control, and are always resampled.
+  pub fn new(
+    tx: HeapProd<f32>,
+    config: &CaptureConfig,
+    channel_map: Option<ChannelMap>,
+    channels: usize,
+    gain: f32,
+    drift: Option<Arc<DriftControl>>,
+  ) -> anyhow::Result<Self> {
+    let resampler = match (&drift, config.needs_resampling()) {
+      (Some(_), _) => Some(Resampler::new(config.sample_rate(), DEFAULT_SR as u32, channels, MAX_DRIFT)?),
+      (None, true) => Some(Resampler::new(config.sample_rate(), DEFAULT_SR as u32, channels, 1.0)?),
+      (None, false) => None,
+    };
+    Ok(Self {
+      tx,
+      channel_map,
+      resampler,
+      drift,
+      gain,
+      scratch: Vec::with_capacity(8192),
+      routed: Vec::with_capacity(8192),
+    })
+  }
+
+  /// Samples pushed to the ringbuffer that the encoder has not consumed yet.
+  pub fn buffered(&self) -> usize { self.tx.occupied_len() }
+
+  pub fn push<T>(&mut self, buf: &[T])
+  where
+    T: SizedSample,
+    f32: FromSample<T>,
+  {
+    self.scratch.clear();
+    let gain = self.gain;
+    self.scratch.extend(buf.iter().map(|s| s.to_sample::<f32>() * gain));
+    let samples = match &self.channel_map {
+      Some(map) => {
+        map.apply(&self.scratch, &mut self.routed);
+        &self.routed
+      }
+      None => &self.scratch,
+    };
+    if let (Some(drift), Some(r)) = (&self.drift, self.resampler.as_mut()) {
+      r.set_ratio_relative(drift.get());
+    }
+    let tx = &mut self.tx;
+    match self.resampler.as_mut() {
+      Some(r) => r.process(samples, |out| { tx.push_slice(out); }),
+      None => { tx.push_slice(samples); }
+    }
+  }
+}
+
+fn build<T>(device: &Device, config: &StreamConfig, mut capture: Capture) -> Result<Stream, BuildStreamError>
+where
+  T: SizedSample,
+  f32: FromSample<T>,
+{
+  device.build_input_stream(
+    config,
+    move |buf: &[T], _info| capture.push(buf),
+    |e| {
+      eprintln!("{e}");
+      std::process::exit(1)
+    },
+    None,
+  )
+}
+
+/// Builds the input stream in the device's sample format.
+pub fn build_input_stream(device: &Device, config: &CaptureConfig, capture: Capture) -> Result<Stream, BuildStreamError> {
+  match config.format {
+    SampleFormat::F32 => build::<f32>(device, &config.stream, capture),
+    SampleFormat::F64 => build::<f64>(device, &config.stream, capture),
+    SampleFormat::I8  => build::<i8>(device, &config.stream, capture),
+    SampleFormat::I16 => build::<i16>(device, &config.stream, capture),
+    SampleFormat::I24 => build::<I24>(device, &config.stream, capture),
+    SampleFormat::I32 => build::<i32>(device, &config.stream, capture),
+    SampleFormat::I64 => build::<i64>(device, &config.stream, capture),
+    SampleFormat::U8  => build::<u8>(device, &config.stream, capture),
+    SampleFormat::U16 => build::<u16>(device, &config.stream, capture),
+    SampleFormat::U32 => build::<u32>(device, &config.stream, capture),
+    SampleFormat::U64 => build::<u64>(device, &config.stream, capture),
+    _ => Err(BuildStreamError::StreamConfigNotSupported),
+  }
+}
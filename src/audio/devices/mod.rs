@@ -0,0 +1,137 @@
+use cpal::{
+  Device, SupportedStreamConfigRange,
+  traits::{DeviceTrait, HostTrait},
+};
+use serde::Serialize;
+
+use crate::DEFAULT_SR;
+
+/// The channels a device needs to offer to be opened for the stream.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct ChannelNeed {
+  pub channels: usize,
+  /// Whether more channels do as well, when only some inputs are routed to the stream.
+  pub at_least: bool,
+}
+
+impl ChannelNeed {
+  pub fn accepts(&self, channels: usize) -> bool {
+    channels == self.channels || (self.at_least && channels > self.channels)
+  }
+}
+
+/// All input devices found on a single cpal host (ALSA, JACK, CoreAudio ...).
+#[derive(Debug, Serialize)]
+pub struct HostReport {
+  pub host: String,
+  pub is_default: bool,
+  pub devices: Vec<DeviceReport>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeviceReport {
+  /// Position in the host's list of input devices, which a numeric `audio_interface`
+  /// selects. Only the default host's devices are captured, those of other hosts have none.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub index: Option<usize>,
+  pub name: String,
+  pub is_default: bool,
+  /// Whether the device can be opened with the stream config tau-radio requests.
+  pub compatible: bool,
+  /// Whether the device lacks native 48kHz support, and will be resampled.
+  pub resampled: bool,
+  pub configs: Vec<ConfigReport>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ConfigReport {
+  pub channels: u16,
+  pub min_sample_rate: u32,
+  pub max_sample_rate: u32,
+  pub sample_format: String,
+}
+
+impl From<&SupportedStreamConfigRange> for ConfigReport {
+  fn from(range: &SupportedStreamConfigRange) -> Self {
+    Self {
+      channels: range.channels(),
+      min_sample_rate: range.min_sample_rate().0,
+      max_sample_rate: range.max_sample_rate().0,
+      sample_format: range.sample_format().to_string(),
+    }
+  }
+}
+
+/// Checks if a supported config range has the channels the stream is configured with.
+/// Samples are converted to f32, and samplerates other than 48kHz are resampled, see [`supports_native_rate`].
+fn satisfies_stream_config(range: &SupportedStreamConfigRange, need: ChannelNeed) -> bool {
+  need.accepts(range.channels() as usize)
+}
+
+fn supports_native_rate(range: &SupportedStreamConfigRange, need: ChannelNeed) -> bool {
+  satisfies_stream_config(range, need)
+    && (range.min_sample_rate().0..=range.max_sample_rate().0).contains(&(DEFAULT_SR as u32))
+}
+
+fn device_report(index: Option<usize>, device: &Device, default_name: Option<&str>, need: ChannelNeed) -> DeviceReport {
+  let name = device.name().unwrap_or_else(|_| String::from("<unknown>"));
+  let configs = device
+    .supported_input_configs()
+    .map(|configs| configs.collect::<Vec<_>>())
+    .unwrap_or_default();
+  DeviceReport {
+    index,
+    is_default: default_name == Some(name.as_str()),
+    compatible: configs.iter().any(|c| satisfies_stream_config(c, need)),
+    resampled: !configs.iter().any(|c| supports_native_rate(c, need)),
+    configs: configs.iter().map(ConfigReport::from).collect(),
+    name,
+  }
+}
+
+/// Collects every input device on every available cpal host.
+pub fn collect(need: ChannelNeed) -> Vec<HostReport> {
+  let default_host = cpal::default_host().id();
+  cpal::available_hosts()
+    .into_iter()
+    .map(|id| {
+      let mut report = HostReport {
+        host: id.name().to_string(),
+        is_default: id == default_host,
+        devices: Vec::new(),
+        error: None,
+      };
+      let host = match cpal::host_from_id(id) {
+        Ok(host) => host,
+        Err(e) => {
+          report.error = Some(e.to_string());
+          return report;
+        }
+      };
+      let default_name = host.default_input_device().and_then(|d| d.name().ok());
+      match host.input_devices() {
+        Ok(devices) => {
+          report.devices = devices
+            .enumerate()
+            .map(|(i, d)| device_report(report.is_default.then_some(i), &d, default_name.as_deref(), need))
+            .collect();
+        }
+        Err(e) => report.error = Some(e.to_string()),
+      }
+      report
+    })
+    .collect()
+}
+
+/// Entry point of the `devices` subcommand, marking the devices that have the channels in `need`.
+pub fn list(json: bool, need: ChannelNeed) -> anyhow::Result<()> {
+  let hosts = collect(need);
+  if json {
+    println!("{}", serde_json::to_string_pretty(&hosts)?);
+  } else {
+    crate::ui::print_device_report(&hosts, need);
+  }
+  Ok(())
+}
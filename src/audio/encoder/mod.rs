@@ -0,0 +1,171 @@
+use clap::ValueEnum;
+use opusenc::{
+  Encoder,
+  options::{Application as OpusApplication, Framesize},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::config::TauConfigError;
+
+/// Frame durations libopusenc accepts, in milliseconds.
+const FRAME_DURATIONS: [(f32, Framesize); 6] = [
+  (2.5, Framesize::Ms2_5),
+  (5.0, Framesize::Ms5),
+  (10.0, Framesize::Ms10),
+  (20.0, Framesize::Ms20),
+  (40.0, Framesize::Ms40),
+  (60.0, Framesize::Ms60),
+];
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum BitrateMode {
+  /// Variable bitrate, the libopus default
+  Vbr,
+  /// Constrained variable bitrate, never overshoots the bitrate by more than a frame
+  Cvbr,
+  /// Constant bitrate
+  Cbr,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Application {
+  /// Favors faithfulness to the input, the libopus default
+  Audio,
+  /// Favors speech intelligibility
+  Voip,
+  /// Lowest possible coding delay, disables the speech modes
+  Lowdelay,
+}
+
+/// Opus encoder settings, applied to the live stream and the local recording separately.
+/// Anything left unset keeps the libopus default.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct EncoderConfig {
+  /// Target bitrate in kbit/s, for all channels together.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub bitrate: Option<u32>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub bitrate_mode: Option<BitrateMode>,
+  /// 0 (fastest) to 10 (best quality).
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub complexity: Option<u8>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub application: Option<Application>,
+  /// Duration of an Opus frame in milliseconds: 2.5, 5, 10, 20, 40 or 60.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub frame_duration: Option<f32>,
+  /// In-band forward error correction, only effective together with `packet_loss`.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub fec: Option<bool>,
+  /// Expected packet loss in percent, 0 to 100.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub packet_loss: Option<u8>,
+  /// Discontinuous transmission, sends less data during silence.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub dtx: Option<bool>,
+}
+
+impl EncoderConfig {
+  pub fn is_default(&self) -> bool { *self == Self::default() }
+
+  /// Overrides the settings that are set in `other`.
+  pub fn merge(&mut self, other: &EncoderConfig) {
+    self.bitrate        = other.bitrate.or(self.bitrate);
+    self.bitrate_mode   = other.bitrate_mode.or(self.bitrate_mode);
+    self.complexity     = other.complexity.or(self.complexity);
+    self.application    = other.application.or(self.application);
+    self.frame_duration = other.frame_duration.or(self.frame_duration);
+    self.fec            = other.fec.or(self.fec);
+    self.packet_loss    = other.packet_loss.or(self.packet_loss);
+    self.dtx            = other.dtx.or(self.dtx);
+  }
+
+  pub fn validate(&self) -> Result<(), TauConfigError> {
+    if let Some(c) = self.complexity {
+      validate_complexity(c)?;
+    }
+    if let Some(l) = self.packet_loss {
+      validate_packet_loss(l)?;
+    }
+    if let Some(ms) = self.frame_duration {
+      framesize(ms)?;
+    }
+    if self.bitrate == Some(0) {
+      return Err(TauConfigError::InvalidEncoder("bitrate must be above 0 kbit/s".to_string()));
+    }
+    Ok(())
+  }
+
+  /// Applies the settings to a freshly created encoder, before any audio is written.
+  pub fn apply(&self, encoder: &mut Encoder) -> anyhow::Result<()> {
+    if let Some(application) = self.application {
+      encoder.set_application(match application {
+        Application::Audio => OpusApplication::Audio,
+        Application::Voip => OpusApplication::Voip,
+        Application::Lowdelay => OpusApplication::RestrictedLowdelay,
+      })?;
+    }
+    if let Some(kbps) = self.bitrate {
+      encoder.set_bitrate(kbps as i32 * 1000)?;
+    }
+    match self.bitrate_mode {
+      Some(BitrateMode::Vbr) => {
+        encoder.set_vbr_enabled(true)?;
+        encoder.set_vbr_constraint(false)?;
+      }
+      Some(BitrateMode::Cvbr) => {
+        encoder.set_vbr_enabled(true)?;
+        encoder.set_vbr_constraint(true)?;
+      }
+      Some(BitrateMode::Cbr) => encoder.set_vbr_enabled(false)?,
+      None => {}
+    }
+    if let Some(c) = self.complexity {
+      encoder.set_complexity(c as i32)?;
+    }
+    if let Some(ms) = self.frame_duration {
+      encoder.set_expert_frame_duration(framesize(ms)?)?;
+    }
+    if let Some(fec) = self.fec {
+      encoder.set_inband_fec(fec)?;
+    }
+    if let Some(l) = self.packet_loss {
+      encoder.set_loss_percentage(l as i32)?;
+    }
+    if let Some(dtx) = self.dtx {
+      encoder.set_dtx(dtx)?;
+    }
+    Ok(())
+  }
+}
+
+pub fn validate_complexity(c: u8) -> Result<u8, TauConfigError> {
+  if c > 10 {
+    return Err(TauConfigError::InvalidEncoder(format!("complexity must be 0 to 10, got {c}")));
+  }
+  Ok(c)
+}
+
+pub fn validate_packet_loss(l: u8) -> Result<u8, TauConfigError> {
+  if l > 100 {
+    return Err(TauConfigError::InvalidEncoder(format!("packet_loss must be 0 to 100 percent, got {l}")));
+  }
+  Ok(l)
+}
+
+pub fn validate_frame_duration(ms: f32) -> Result<f32, TauConfigError> {
+  framesize(ms).map(|_| ms)
+}
+
+fn framesize(ms: f32) -> Result<Framesize, TauConfigError> {
+  FRAME_DURATIONS
+    .iter()
+    .find(|(d, _)| *d == ms)
+    .map(|(_, size)| *size)
+    .ok_or_else(|| TauConfigError::InvalidEncoder(
+      format!("frame_duration must be one of 2.5, 5, 10, 20, 40 or 60 ms, got {ms}")
+    ))
+}
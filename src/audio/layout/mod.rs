@@ -0,0 +1,109 @@
+use crate::util::consts::MAX_CH;
+
+/// Speaker positions of the WAVEFORMATEXTENSIBLE channel order, the order devices deliver
+/// surround channels in. The stream is kept in this order up to the Opus encoders.
+pub fn channel_mask(channels: usize) -> u32 {
+  match channels {
+    1 => 0x4,
+    2 => 0x3,
+    3 => 0x7,
+    4 => 0x33,
+    5 => 0x37,
+    6 => 0x3F,
+    7 => 0x70F,
+    _ => 0x63F,
+  }
+}
+
+/// The speaker of each channel, as its bit of [`channel_mask`]. `None` past [`MAX_CH`]
+/// channels, which have no standard layout.
+pub fn speakers(channels: usize) -> Option<Vec<u32>> {
+  (channels <= MAX_CH).then(|| {
+    let mask = channel_mask(channels);
+    (0..u32::BITS).map(|bit| 1 << bit).filter(|speaker| mask & speaker != 0).collect()
+  })
+}
+
+/// Opus channel mapping family 1 expects the Vorbis channel order, which puts the centre
+/// between the front channels and the LFE last. Vorbis channel `i` is device channel `order[i]`.
+pub fn vorbis_order(channels: usize) -> &'static [usize] {
+  match channels {
+    3 => &[0, 2, 1],
+    5 => &[0, 2, 1, 3, 4],
+    6 => &[0, 2, 1, 4, 5, 3],
+    7 => &[0, 2, 1, 5, 6, 4, 3],
+    8 => &[0, 2, 1, 6, 7, 4, 5, 3],
+    // Mono, stereo and quad are the same in both orders
+    n => &[0, 1, 2, 3][..n],
+  }
+}
+
+/// Interleaved samples in device order, reordered for an Opus encoder.
+pub struct VorbisOrder {
+  order: &'static [usize],
+  buf: Vec<f32>,
+}
+
+impl VorbisOrder {
+  pub fn new(channels: usize) -> Self {
+    Self { order: vorbis_order(channels), buf: Vec::new() }
+  }
+
+  /// `pcm` in Vorbis order, as is where both orders are the same.
+  pub fn apply<'a>(&'a mut self, pcm: &'a [f32]) -> &'a [f32] {
+    if self.order.iter().enumerate().all(|(i, src)| i == *src) {
+      return pcm;
+    }
+    self.buf.clear();
+    for frame in pcm.chunks_exact(self.order.len()) {
+      self.buf.extend(self.order.iter().map(|src| frame[*src]));
+    }
+    &self.buf
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Speakers in the order of the WAVEFORMATEXTENSIBLE mask bits.
+  const WAVE_SPEAKERS: [&str; 11] = ["FL", "FR", "FC", "LFE", "BL", "BR", "FLC", "FRC", "BC", "SL", "SR"];
+
+  fn wave_speakers(channels: usize) -> Vec<&'static str> {
+    let mask = channel_mask(channels);
+    WAVE_SPEAKERS.iter().enumerate().filter(|(bit, _)| mask & 1 << bit != 0).map(|(_, s)| *s).collect()
+  }
+
+  #[test]
+  fn reorders_for_opus() {
+    // The Vorbis channel order, section 4.3.9 of the Vorbis I specification
+    let vorbis: [&[&str]; 8] = [
+      &["FC"],
+      &["FL", "FR"],
+      &["FL", "FC", "FR"],
+      &["FL", "FR", "BL", "BR"],
+      &["FL", "FC", "FR", "BL", "BR"],
+      &["FL", "FC", "FR", "BL", "BR", "LFE"],
+      &["FL", "FC", "FR", "SL", "SR", "BC", "LFE"],
+      &["FL", "FC", "FR", "SL", "SR", "BL", "BR", "LFE"],
+    ];
+    for (i, expected) in vorbis.iter().enumerate() {
+      let channels = i + 1;
+      let device = wave_speakers(channels);
+      assert_eq!(device.len(), channels);
+      let reordered: Vec<&str> = vorbis_order(channels).iter().map(|src| device[*src]).collect();
+      assert_eq!(reordered, *expected, "{channels} channels");
+    }
+  }
+
+  #[test]
+  fn reorders_5_1_frames() {
+    // FL FR FC LFE BL BR, two frames
+    let device = [1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 10.0, 20.0, 30.0, 40.0, 50.0, 60.0];
+    let mut order = VorbisOrder::new(6);
+    // FL FC FR BL BR LFE
+    assert_eq!(order.apply(&device), [1.0, 3.0, 2.0, 5.0, 6.0, 4.0, 10.0, 30.0, 20.0, 50.0, 60.0, 40.0]);
+    let stereo = [0.5, -0.5];
+    assert_eq!(VorbisOrder::new(2).apply(&stereo), stereo);
+  }
+}
@@ -0,0 +1,116 @@
+use std::{
+  sync::{
+    Arc,
+    atomic::{AtomicBool, AtomicU32, Ordering},
+  },
+  thread::sleep,
+  time::{Duration, Instant},
+};
+
+use ringbuf::{
+  HeapCons, HeapProd,
+  traits::{Consumer, Observer, Producer},
+};
+
+use crate::util::consts::FRAME_LEN;
+
+/// Frames mixed per iteration, 10ms at 48kHz.
+const BLOCK_LEN: usize = FRAME_LEN / 2;
+/// Fill level, in blocks, each secondary device is steered towards.
+/// Gives the adaptive resampler headroom in both directions.
+const TARGET_BLOCKS: usize = 4;
+/// How far the resampling ratio of a secondary device may stray from its nominal ratio.
+pub const MAX_DRIFT: f64 = 1.005;
+/// Proportional gain of the drift controller, per block of fill error.
+const DRIFT_GAIN: f64 = 0.0002;
+/// Smoothing of the measured fill level, the jitter of device callbacks is far larger than the drift.
+const FILL_SMOOTHING: f64 = 0.01;
+/// How long to wait for all devices to deliver audio before mixing starts.
+const ALIGN_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Relative resampling ratio, written by the mixer and read by a device's capture callback.
+pub struct DriftControl(AtomicU32);
+
+impl DriftControl {
+  pub fn new() -> Arc<Self> { Arc::new(Self(AtomicU32::new(1.0f32.to_bits()))) }
+  pub fn get(&self) -> f64 { f32::from_bits(self.0.load(Ordering::Relaxed)) as f64 }
+  fn set(&self, ratio: f64) { self.0.store((ratio as f32).to_bits(), Ordering::Relaxed) }
+}
+
+/// One device's ringbuffer, as seen by the mixer.
+pub struct MixerInput {
+  pub rx: HeapCons<f32>,
+  /// `None` for the first device, which is the master clock of the mix.
+  pub drift: Option<Arc<DriftControl>>,
+  fill: f64,
+}
+
+impl MixerInput {
+  pub fn new(rx: HeapCons<f32>, drift: Option<Arc<DriftControl>>) -> Self {
+    Self { rx, drift, fill: (BLOCK_LEN * TARGET_BLOCKS) as f64 }
+  }
+
+  /// Nudges the device's resampler so its fill level stays around [`TARGET_BLOCKS`].
+  /// A device running fast fills up, and has its ratio lowered to produce fewer samples.
+  fn steer(&mut self, channels: usize) {
+    let Some(drift) = &self.drift else { return };
+    let frames = (self.rx.occupied_len() / channels) as f64;
+    self.fill += (frames - self.fill) * FILL_SMOOTHING;
+    let error = (self.fill - (BLOCK_LEN * TARGET_BLOCKS) as f64) / BLOCK_LEN as f64;
+    drift.set((1.0 - error * DRIFT_GAIN).clamp(1.0 / MAX_DRIFT, MAX_DRIFT));
+  }
+}
+
+/// Waits until every device has buffered its target fill, then drops any surplus
+/// so that all inputs start out aligned.
+fn align(shutdown: &AtomicBool, inputs: &mut [MixerInput], channels: usize) {
+  let target = BLOCK_LEN * TARGET_BLOCKS * channels;
+  let started = Instant::now();
+  while !shutdown.load(Ordering::SeqCst)
+    && started.elapsed() < ALIGN_TIMEOUT
+    && inputs.iter().any(|i| i.rx.occupied_len() < target)
+  {
+    sleep(Duration::from_millis(2));
+  }
+  for input in inputs.iter_mut() {
+    let surplus = input.rx.occupied_len().saturating_sub(target);
+    input.rx.skip(surplus - surplus % channels);
+  }
+}
+
+/// Sums all devices into one stream, per device gain is applied in the capture callback.
+/// The first device paces the mix, the others are kept in step by adaptive resampling.
+pub fn mix_loop(
+  shutdown: Arc<AtomicBool>,
+  mut inputs: Vec<MixerInput>,
+  tx: &mut HeapProd<f32>,
+  channels: usize
+) {
+  let block = BLOCK_LEN * channels;
+  let mut mix = vec![0.0f32; block];
+  let mut buf = vec![0.0f32; block];
+
+  align(&shutdown, &mut inputs, channels);
+
+  loop {
+    if shutdown.load(Ordering::SeqCst) { break; }
+    // The master is kept at the same fill level the others are steered towards, so all inputs line up
+    if inputs[0].rx.occupied_len() < block * (TARGET_BLOCKS + 1) {
+      sleep(Duration::from_millis(2));
+      continue;
+    }
+
+    mix.iter_mut().for_each(|s| *s = 0.0);
+    for input in inputs.iter_mut() {
+      input.steer(channels);
+      // A device that has fallen behind contributes silence for the missing part
+      let available = input.rx.occupied_len();
+      let n = input.rx.pop_slice(&mut buf[..(available - available % channels).min(block)]);
+      mix[..n].iter_mut().zip(&buf[..n]).for_each(|(m, s)| *m += s);
+      // A device that ran far ahead is realigned, rather than letting latency build up
+      let overflow = input.rx.occupied_len().saturating_sub(block * TARGET_BLOCKS * 4);
+      input.rx.skip(overflow - overflow % channels);
+    }
+    tx.push_slice(&mix);
+  }
+}
@@ -1,129 +1,246 @@
 
+pub mod capture;
+pub mod devices;
+pub mod encoder;
+pub mod layout;
+pub mod mixer;
+pub mod recorder;
+pub mod resample;
+pub mod routing;
+pub mod source;
+
 use crate::{
   AUDIO_INTERFACE_NOT_FOUND,
   DEFAULT_INPUT,
-  DEFAULT_CH,
   DEFAULT_SR,  
   err::default_not_found,
   Arc, 
   AtomicBool,
   Ordering,
-  PathBuf,
 };
 
 use std::{
+  path::Path,
   time::Duration, 
   process::exit,
   thread::sleep
 };
-use opusenc::{Comments, Encoder, RecommendedTag};
+use dialoguer::Select;
+use inline_colorization::{color_bright_yellow, color_reset, color_yellow};
+use regex::RegexBuilder;
+use opusenc::{Encoder, MappingFamily};
 use crossbeam::channel::{Receiver, Sender};
 use ringbuf::traits::Consumer;
 
 use cpal::{
-    Device, Host,
+    Device, Host, SampleFormat, Stream,
     traits::{DeviceTrait, HostTrait},
 };
+use ringbuf::HeapProd;
+
+use crate::config::Config;
+use crate::metadata::StreamMetadata;
+use capture::Capture;
+use encoder::EncoderConfig;
+use layout::VorbisOrder;
+use mixer::DriftControl;
+use recorder::{RecordingSettings, create_recorder};
+
+/// The device picked from the host, and whether the user had to choose it among several matches.
+pub struct SelectedDevice {
+  pub device: Device,
+  pub name: String,
+  pub picked: bool,
+}
 
 /// Searches and matches on the audio interfaces available to the host.
+/// `audio_interface` is matched, in order, as an exact name, as an index into the host's
+/// list of input devices, as a case-insensitive substring and lastly as a case-insensitive regex.
+/// If more than one device matches, the user is prompted to pick one.
 /// Returns an error if unable to access audio input devices, 
 /// or if the [`DEFAULT_INPUT`] or any other interface was not available on the host.
-pub fn find_audio_device(host: &Host, audio_interface: &str) -> anyhow::Result<Device> {
+pub fn find_audio_device(host: &Host, audio_interface: &str) -> anyhow::Result<SelectedDevice> {
   let devices = host.input_devices().map_err(|err| {
     anyhow::anyhow!(
       "Could not list input devices: {err}\n\
        Make sure your audio hardware is connected and accessible"
     )
   })?;
-  if let Some(dev) = devices
+  let mut devices: Vec<(Device, String)> = devices
     .filter_map(|d| d.name().ok().map(|n| (d, n)))
-    .find(|(_, name)| name == audio_interface)
-    .map(|(d, _)| d)
-  {
-    return Ok(dev);
+    .collect();
+
+  if let Some(i) = devices.iter().position(|(_, name)| name == audio_interface) {
+    return Ok(selected(devices.swap_remove(i), false));
   }
-  if audio_interface == DEFAULT_INPUT {
-    Err(anyhow::anyhow!("{}", default_not_found()))
-  } else {
-    Err(anyhow::anyhow!("{}", AUDIO_INTERFACE_NOT_FOUND))
+
+  if let Ok(i) = audio_interface.trim().parse::<usize>() && i < devices.len() {
+    return Ok(selected(devices.swap_remove(i), false));
+  }
+
+  let needle = audio_interface.to_lowercase();
+  let mut matches: Vec<(Device, String)> = devices
+    .iter()
+    .filter(|(_, name)| name.to_lowercase().contains(&needle))
+    .cloned()
+    .collect();
+
+  if matches.is_empty() && let Ok(re) = RegexBuilder::new(audio_interface).case_insensitive(true).build() {
+    matches = devices
+      .into_iter()
+      .filter(|(_, name)| re.is_match(name))
+      .collect();
+  }
+
+  match matches.len() {
+    0 if audio_interface == DEFAULT_INPUT => Err(anyhow::anyhow!("{}", default_not_found())),
+    0 => Err(anyhow::anyhow!("{}", AUDIO_INTERFACE_NOT_FOUND)),
+    1 => Ok(selected(matches.swap_remove(0), false)),
+    _ => {
+      let names: Vec<&str> = matches.iter().map(|(_, name)| name.as_str()).collect();
+      let choice = Select::new()
+        .with_prompt(format!(
+          "{color_bright_yellow}Several audio interfaces match '{audio_interface}', pick one{color_reset}"
+        ))
+        .items(&names)
+        .default(0)
+        .interact()?;
+      Ok(selected(matches.swap_remove(choice), true))
+    }
   }
 }
 
-fn create_encoder(filename: &str) -> Encoder {
-  Encoder::create_pull(
-    Comments::create()
-        .add(RecommendedTag::Title, filename.to_string())
-        .unwrap(),
-    DEFAULT_SR,
-    DEFAULT_CH,
-    opusenc::MappingFamily::MonoStereo,
-  )
-  .unwrap_or_else(|err| {
-    eprintln!("Could not create new realtime .ogg encoder: {err}");
-    exit(1)
-  })
+/// Opens the `index`th `audio_interface` of the config, feeding `tx` with the stream's
+/// channel count at [`DEFAULT_SR`]. Devices mixed into another device's clock pass a `drift` control.
+pub fn open_input(
+  host: &Host,
+  config: &mut Config,
+  index: usize,
+  tx: HeapProd<f32>,
+  drift: Option<Arc<DriftControl>>,
+) -> anyhow::Result<Stream> {
+  let selected = find_audio_device(host, &config.audio_interface[index].name)?;
+  if selected.picked && let Err(e) = config.offer_to_save_audio_interface(index, &selected.name) {
+    eprintln!("{color_yellow}Could not save audio_interface to config.toml:{color_reset} {e}");
+  }
+  config.audio_interface[index].name = selected.name;
+  let input = &config.audio_interface[index];
+
+  let capture_config = capture::input_config(&selected.device, config.channel_need(input));
+  let device_channels = capture_config.stream.channels as usize;
+  let channel_map = config.channel_map(input, device_channels)?;
+  let capture = Capture::new(tx, &capture_config, channel_map, config.channels, input.gain, drift)?;
+  let stream = capture::build_input_stream(&selected.device, &capture_config, capture)
+    .map_err(crate::err::handle_input_build_error)?;
+
+  let routes = if input.routes.is_empty() { &config.routes } else { &input.routes };
+  if !routes.is_empty() {
+    crate::ui::print_routes(&input.name, device_channels, routes);
+  }
+  if capture_config.format != SampleFormat::F32 {
+    crate::ui::print_sample_conversion(&input.name, capture_config.format);
+  }
+  if capture_config.needs_resampling() {
+    crate::ui::print_resampling(&input.name, capture_config.sample_rate(), DEFAULT_SR as u32);
+  }
+  Ok(stream)
+}
+
+fn selected((device, name): (Device, String), picked: bool) -> SelectedDevice {
+  SelectedDevice { device, name, picked }
 }
 
-fn create_recorder(path: &PathBuf, filename: &str) -> Encoder {
-  Encoder::create_file(
-    path,
-    // filename.clone().as_str(),
-    Comments::create()
-      .add(RecommendedTag::Title, filename.to_string())
-      .unwrap(),
+/// Mono and stereo use channel mapping family 0, anything above needs the
+/// surround mapping (family 1), fed through [`VorbisOrder`].
+fn mapping_family(channels: usize) -> MappingFamily {
+  if channels <= 2 { MappingFamily::MonoStereo } else { MappingFamily::Surround }
+}
+
+fn create_encoder(metadata: &StreamMetadata, channels: usize, settings: &EncoderConfig) -> Encoder {
+  let comments = metadata.comments(None, true).unwrap_or_else(|err| {
+    eprintln!("Could not tag realtime .ogg encoder: {err}");
+    exit(1)
+  });
+  let mut encoder = Encoder::create_pull(
+    comments,
     DEFAULT_SR,
-    DEFAULT_CH,
-    opusenc::MappingFamily::MonoStereo,
+    channels,
+    mapping_family(channels),
   )
   .unwrap_or_else(|err| {
-    eprintln!("Could not create new local .ogg file: {err}");
+    eprintln!("Could not create new realtime .ogg encoder: {err}");
     exit(1)
-  })
+  });
+  if let Err(err) = settings.apply(&mut encoder) {
+    eprintln!("Could not apply stream_encoder settings: {err}");
+    exit(1)
+  }
+  encoder
 }
 
-
 pub(crate) fn record_audio(
-  shutdown: Arc<AtomicBool>,
-  filename: Arc<String>,
+  metadata: Arc<StreamMetadata>,
   in_rx: &Receiver<f32>,
-  path: &PathBuf,
-  framesize: usize
+  path: &Path,
+  channels: usize,
+  framesize: usize,
+  settings: &RecordingSettings,
 ) {
-  let mut encoder = create_recorder(path, &filename);
+  let mut recorder = create_recorder(path, &metadata, channels, settings).unwrap_or_else(|err| {
+    eprintln!("Could not create new local recording {}: {err}", path.display());
+    exit(1)
+  });
+  let updates = metadata.subscribe();
   let mut buf = Vec::with_capacity(framesize);
-  loop {
-    if shutdown.load(Ordering::SeqCst) { break; }
-    if let Ok(sample) = in_rx.recv() {
-      buf.push(sample);
-    }
+  // Runs until the capture loop hangs up at shutdown, so the recording gets all of the captured audio
+  while let Ok(sample) = in_rx.recv() {
+    buf.push(sample);
     if buf.len() == framesize {
-      encoder
-        .write_float(&buf)
-        .expect("block not a multiple of input channels");
+      if let Err(e) = recorder.write(&buf) {
+        eprintln!("Could not write to local recording: {e}");
+        break;
+      }
       buf.clear();
+      for now in updates.try_iter() {
+        if let Err(e) = recorder.now_playing(&now, &metadata) {
+          eprintln!("Could not tag local recording: {e}");
+        }
+      }
     }
   }
+  buf.truncate(buf.len() - buf.len() % channels);
+  if let Err(e) = recorder.write(&buf).and_then(|_| recorder.finish()) {
+    eprintln!("Could not finish local recording: {e}");
+  }
 }
 
 pub(crate) fn encode_audio(
-  shutdown: Arc<AtomicBool>,
-  filename: Arc<String>,
+  metadata: Arc<StreamMetadata>,
   in_rx: &Receiver<f32>,
   opus_tx: &Sender<Vec<u8>>,
-  framesize: usize
+  channels: usize,
+  framesize: usize,
+  settings: &EncoderConfig,
 ) {
-  let mut encoder = create_encoder(&filename);
+  let mut encoder = create_encoder(&metadata, channels, settings);
+  let mut order = VorbisOrder::new(channels);
+  let updates = metadata.subscribe();
   let mut buf = Vec::with_capacity(framesize);
-  loop {
-    if shutdown.load(Ordering::SeqCst) { break; }
-    if let Ok(sample) = in_rx.recv() {
-      buf.push(sample);
-    }
+  // Runs until the capture loop hangs up at shutdown, then ends the stream
+  while let Ok(sample) = in_rx.recv() {
+    buf.push(sample);
     if buf.len() == framesize {
       encoder
-        .write_float(&buf)
+        .write_float(order.apply(&buf))
         .expect("block not a multiple of input channels");
       buf.clear();
+      // New tags start a new chained stream, which tau-tower passes on to the listeners.
+      // Without the cover, that would be sent again on every update and reconnection
+      for now in updates.try_iter() {
+        if let Err(e) = metadata.comments(Some(&now), false).and_then(|c| Ok(encoder.chain_current(c)?)) {
+          eprintln!("Could not update stream metadata: {e}");
+        }
+      }
       // flush forces encoder to return a page, even if not ready.
       // true is used when realtime streaming is more important than stability.
       if let Some(page) = encoder.get_page(true) {
@@ -134,24 +251,38 @@ pub(crate) fn encode_audio(
           Could not append encoded ogg to shared \
           ringbuffer to websocket thread: {e}"
           );
-          break;
+          return;
           // exit(1);
         }
       }
     }
   }
+  // The last, partial frame, and the pages that end the Ogg stream
+  buf.truncate(buf.len() - buf.len() % channels);
+  if let Err(e) = encoder.write_float(order.apply(&buf)).and_then(|_| encoder.drain()) {
+    eprintln!("Could not end the stream: {e}");
+    return;
+  }
+  while let Some(page) = encoder.get_page(true) {
+    if opus_tx.send(page.to_vec()).is_err() { return; }
+  }
 }
 
 /// Fans out the audio stream to (optional) multiple consumers - Broadcast style!
 pub(crate) fn audio_capture_loop(shutdown: Arc<AtomicBool>, producer: &mut (impl Consumer<Item = f32> + Send + 'static), consumers: &[Sender<f32>]) {
+  let fan_out = |sample: f32| consumers.iter().for_each(|c| {
+    if let Err(e) = c.send(sample) {
+      eprintln!("Could not fan out audio stream: {e}")
+    }
+  });
   loop {
-    if shutdown.load(Ordering::SeqCst) { break; }
+    if shutdown.load(Ordering::SeqCst) {
+      // What was captured before the shutdown still goes out
+      while let Some(sample) = producer.try_pop() { fan_out(sample); }
+      break;
+    }
     if let Some(sample) = producer.try_pop() {
-      consumers.iter().for_each(|c| {
-        if let Err(e) = c.send(sample) {
-          eprintln!("Could not fan out audio stream: {e}")
-        }
-      });
+      fan_out(sample);
     } else {
       sleep(Duration::from_millis(2));
     }
@@ -0,0 +1,96 @@
+use std::{
+  fs::File,
+  io::Write,
+  path::{Path, PathBuf},
+};
+
+use crate::{DEFAULT_SR, metadata::{NowPlaying, StreamMetadata}};
+use super::Recorder;
+
+/// CUE sheets address audio in frames of 1/75 second.
+const CUE_FPS: u64 = 75;
+/// Track numbers are two digits.
+const MAX_TRACKS: u32 = 99;
+
+/// Marks the now playing updates of a FLAC or WAV recording as tracks of a CUE
+/// sheet next to it, as these formats cannot be chained like Ogg Opus.
+/// The sheet is only written once the first update arrives.
+pub struct CueChapters<R> {
+  recorder: R,
+  path: PathBuf,
+  audio: String,
+  title: String,
+  performer: Option<String>,
+  channels: usize,
+  frames: u64,
+  file: Option<File>,
+  tracks: u32,
+}
+
+impl<R: Recorder> CueChapters<R> {
+  /// `tags` are those of the recording, before any update.
+  pub fn new(recorder: R, path: &Path, tags: &[(&str, String)], channels: usize) -> Self {
+    let tag = |key| tags.iter().find(|(k, _)| *k == key).map(|(_, v)| v.clone());
+    Self {
+      recorder,
+      path: path.with_extension("cue"),
+      audio: path.file_name().map(|f| f.to_string_lossy().into_owned()).unwrap_or_default(),
+      title: tag("TITLE").unwrap_or_default(),
+      performer: tag("ARTIST"),
+      channels,
+      frames: 0,
+      file: None,
+      tracks: 0,
+    }
+  }
+
+  fn add_track(&mut self, title: &str, performer: Option<&str>, at: u64) -> std::io::Result<()> {
+    let Some(file) = &mut self.file else { return Ok(()) };
+    self.tracks += 1;
+    let ff = at * CUE_FPS / DEFAULT_SR as u64;
+    writeln!(file, "  TRACK {:02} AUDIO", self.tracks)?;
+    writeln!(file, "    TITLE \"{}\"", quote(title))?;
+    if let Some(performer) = performer {
+      writeln!(file, "    PERFORMER \"{}\"", quote(performer))?;
+    }
+    writeln!(file, "    INDEX 01 {:02}:{:02}:{:02}", ff / CUE_FPS / 60, ff / CUE_FPS % 60, ff % CUE_FPS)?;
+    file.flush()
+  }
+}
+
+impl<R: Recorder> Recorder for CueChapters<R> {
+  fn write(&mut self, pcm: &[f32]) -> anyhow::Result<()> {
+    self.frames += (pcm.len() / self.channels) as u64;
+    self.recorder.write(pcm)
+  }
+
+  fn finish(&mut self) -> anyhow::Result<()> {
+    self.recorder.finish()
+  }
+
+  fn now_playing(&mut self, now: &NowPlaying, _metadata: &StreamMetadata) -> anyhow::Result<()> {
+    if self.tracks == MAX_TRACKS { return Ok(()) }
+    if self.file.is_none() {
+      let mut file = File::create(&self.path)?;
+      writeln!(file, "TITLE \"{}\"", quote(&self.title))?;
+      if let Some(performer) = &self.performer {
+        writeln!(file, "PERFORMER \"{}\"", quote(performer))?;
+      }
+      writeln!(file, "FILE \"{}\" WAVE", quote(&self.audio))?;
+      self.file = Some(file);
+      // Whatever was on air before the first update
+      if self.frames * CUE_FPS / DEFAULT_SR as u64 > 0 {
+        let (title, performer) = (self.title.clone(), self.performer.clone());
+        self.add_track(&title, performer.as_deref(), 0)?;
+      }
+    }
+    let title = now.title.as_deref().or(now.album.as_deref()).unwrap_or(&self.title).to_string();
+    self.add_track(&title, now.artist.as_deref(), self.frames)?;
+    Ok(())
+  }
+}
+
+/// CUE strings cannot contain double quotes.
+fn quote(s: &str) -> String {
+  s.replace('"', "'")
+}
@@ -0,0 +1,474 @@
+use ogg::{PacketWriteEndInfo, PacketWriter};
+
+use std::{
+  fs::File,
+  io::{self, BufWriter, Seek, SeekFrom, Write},
+  path::Path,
+  time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{DEFAULT_SR, metadata::Picture};
+use super::{Recorder, quantize};
+
+/// Samples per channel in a FLAC frame, the same as the reference encoder.
+const BLOCK_LEN: usize = 4096;
+/// Highest order of the fixed predictors.
+const MAX_PREDICTOR_ORDER: usize = 4;
+/// Highest Rice partition order tried per subframe.
+const MAX_PARTITION_ORDER: u32 = 8;
+/// Byte offset of the STREAMINFO block in a native FLAC file, right after the `fLaC` marker.
+const STREAMINFO_OFFSET: u64 = 4;
+const STREAMINFO_LEN: usize = 34;
+const VENDOR: &str = concat!("tau-radio ", env!("CARGO_PKG_VERSION"));
+
+/// Writes big endian bit fields, as used throughout FLAC.
+struct BitWriter {
+  buf: Vec<u8>,
+  acc: u64,
+  bits: u32,
+}
+
+impl BitWriter {
+  fn new() -> Self { Self { buf: Vec::with_capacity(BLOCK_LEN * 4), acc: 0, bits: 0 } }
+
+  /// Writes the lowest `n` bits of `value`, `n` is at most 32.
+  fn write(&mut self, value: u64, n: u32) {
+    if n == 0 { return }
+    self.acc = (self.acc << n) | (value & ((1 << n) - 1));
+    self.bits += n;
+    while self.bits >= 8 {
+      self.bits -= 8;
+      self.buf.push((self.acc >> self.bits) as u8);
+    }
+    self.acc &= (1 << self.bits) - 1;
+  }
+
+  fn write_signed(&mut self, value: i64, n: u32) { self.write(value as u64, n) }
+
+  fn write_unary(&mut self, mut zeros: u64) {
+    while zeros >= 32 {
+      self.write(0, 32);
+      zeros -= 32;
+    }
+    self.write(1, zeros as u32 + 1);
+  }
+
+  fn write_rice(&mut self, value: u64, k: u32) {
+    self.write_unary(value >> k);
+    self.write(value, k);
+  }
+
+  /// Frame numbers are coded like UTF-8 characters, extended to 36 bits.
+  fn write_utf8(&mut self, value: u64) {
+    if value < 0x80 {
+      return self.write(value, 8);
+    }
+    let len = match value {
+      0x80..0x800 => 2,
+      0x800..0x10000 => 3,
+      0x10000..0x200000 => 4,
+      0x200000..0x4000000 => 5,
+      0x4000000..0x80000000 => 6,
+      _ => 7,
+    };
+    self.write(((0xFF00 >> len) & 0xFF) | (value >> (6 * (len - 1))), 8);
+    for i in (0..len - 1).rev() {
+      self.write(0x80 | ((value >> (6 * i)) & 0x3F), 8);
+    }
+  }
+
+  fn align(&mut self) {
+    if self.bits > 0 { self.write(0, 8 - self.bits) }
+  }
+}
+
+fn crc8(data: &[u8]) -> u8 {
+  data.iter().fold(0u8, |crc, b| {
+    (0..8).fold(crc ^ b, |c, _| if c & 0x80 != 0 { (c << 1) ^ 0x07 } else { c << 1 })
+  })
+}
+
+fn crc16(data: &[u8]) -> u16 {
+  data.iter().fold(0u16, |crc, b| {
+    (0..8).fold(crc ^ ((*b as u16) << 8), |c, _| if c & 0x8000 != 0 { (c << 1) ^ 0x8005 } else { c << 1 })
+  })
+}
+
+fn zigzag(r: i64) -> u64 { ((r << 1) ^ (r >> 63)) as u64 }
+
+/// Residual of the fixed polynomial predictor of `order`, for every sample after the warmup.
+fn residual(x: &[i64], order: usize, out: &mut Vec<i64>) {
+  out.clear();
+  out.extend((order..x.len()).map(|i| match order {
+    0 => x[i],
+    1 => x[i] - x[i - 1],
+    2 => x[i] - 2 * x[i - 1] + x[i - 2],
+    3 => x[i] - 3 * x[i - 1] + 3 * x[i - 2] - x[i - 3],
+    _ => x[i] - 4 * x[i - 1] + 6 * x[i - 2] - 4 * x[i - 3] + x[i - 4],
+  }));
+}
+
+fn rice_bits(residual: &[i64], k: u32) -> u64 {
+  residual.iter().map(|r| (zigzag(*r) >> k) + 1 + k as u64).sum()
+}
+
+/// Best Rice parameter for one partition, estimated from the mean and refined by one step.
+fn rice_parameter(residual: &[i64]) -> (u32, u64) {
+  let mean = residual.iter().map(|r| zigzag(*r)).sum::<u64>() / residual.len().max(1) as u64;
+  let guess = (u64::BITS - mean.leading_zeros()).min(30);
+  (guess.saturating_sub(1)..=guess)
+    .map(|k| (k, rice_bits(residual, k)))
+    .min_by_key(|(_, bits)| *bits)
+    .unwrap_or((0, 0))
+}
+
+/// How the residual of a subframe is split into Rice partitions.
+struct Partitions {
+  order: u32,
+  params: Vec<u32>,
+  /// Size of the coded residual, including the partition headers.
+  bits: u64,
+}
+
+fn partition(residual: &[i64], block_len: usize, predictor_order: usize, order: u32) -> Partitions {
+  let len = block_len >> order;
+  let mut start = 0;
+  let mut params = Vec::with_capacity(1 << order);
+  // Coding method and partition order
+  let mut bits = 6;
+  for i in 0..1usize << order {
+    // The warmup samples are taken from the first partition
+    let end = (i + 1) * len - predictor_order;
+    let (k, b) = rice_parameter(&residual[start..end]);
+    params.push(k);
+    bits += 5 + b;
+    start = end;
+  }
+  Partitions { order, params, bits }
+}
+
+fn best_partitions(residual: &[i64], block_len: usize, predictor_order: usize) -> Partitions {
+  let mut best = partition(residual, block_len, predictor_order, 0);
+  for order in 1..=MAX_PARTITION_ORDER {
+    let parts = 1 << order;
+    if !block_len.is_multiple_of(parts) || block_len / parts <= predictor_order { break }
+    let candidate = partition(residual, block_len, predictor_order, order);
+    if candidate.bits < best.bits { best = candidate }
+  }
+  best
+}
+
+/// Codes one channel of a block as a constant, fixed predictor or verbatim subframe, whichever is smallest.
+fn write_subframe(w: &mut BitWriter, x: &[i64], bps: u32, scratch: &mut Vec<i64>) {
+  if x.iter().all(|s| *s == x[0]) {
+    // Zero padding bit, CONSTANT, no wasted bits
+    w.write(0x00, 8);
+    w.write_signed(x[0], bps);
+    return;
+  }
+
+  let mut best: Option<(usize, Partitions, u64)> = None;
+  for order in 0..=MAX_PREDICTOR_ORDER.min(x.len() - 1) {
+    residual(x, order, scratch);
+    let partitions = best_partitions(scratch, x.len(), order);
+    let bits = partitions.bits + order as u64 * bps as u64;
+    if best.as_ref().is_none_or(|(_, _, b)| bits < *b) {
+      best = Some((order, partitions, bits));
+    }
+  }
+
+  match best {
+    Some((order, partitions, bits)) if bits < x.len() as u64 * bps as u64 => {
+      // FIXED of `order`
+      w.write(0x10 | ((order as u64) << 1), 8);
+      x[..order].iter().for_each(|s| w.write_signed(*s, bps));
+      residual(x, order, scratch);
+      // RICE2, 5 bit parameters
+      w.write(0b01, 2);
+      w.write(partitions.order as u64, 4);
+      let len = x.len() >> partitions.order;
+      let mut start = 0;
+      for (i, k) in partitions.params.iter().enumerate() {
+        let end = (i + 1) * len - order;
+        w.write(*k as u64, 5);
+        scratch[start..end].iter().for_each(|r| w.write_rice(zigzag(*r), *k));
+        start = end;
+      }
+    }
+    _ => {
+      // VERBATIM
+      w.write(0x02, 8);
+      x.iter().for_each(|s| w.write_signed(*s, bps));
+    }
+  }
+}
+
+fn block_header(last: bool, kind: u8, len: usize) -> [u8; 4] {
+  [((last as u8) << 7) | kind, (len >> 16) as u8, (len >> 8) as u8, len as u8]
+}
+
+/// VORBIS_COMMENT metadata block, including its header. Comes first after STREAMINFO,
+/// as the Ogg mapping requires.
+fn vorbis_comment(tags: &[(&str, String)], last: bool) -> Vec<u8> {
+  let mut body = Vec::new();
+  body.extend((VENDOR.len() as u32).to_le_bytes());
+  body.extend(VENDOR.as_bytes());
+  body.extend((tags.len() as u32).to_le_bytes());
+  for (key, value) in tags {
+    let comment = format!("{key}={value}");
+    body.extend((comment.len() as u32).to_le_bytes());
+    body.extend(comment.as_bytes());
+  }
+  let mut block = block_header(last, 4, body.len()).to_vec();
+  block.extend(body);
+  block
+}
+
+/// PICTURE metadata block with the front cover, including its header.
+fn picture(cover: &Picture, last: bool) -> Vec<u8> {
+  let mut body = Vec::with_capacity(cover.data.len() + 64);
+  // Picture type 3, front cover
+  body.extend(3u32.to_be_bytes());
+  body.extend((cover.mime.len() as u32).to_be_bytes());
+  body.extend(cover.mime.as_bytes());
+  // No description
+  body.extend(0u32.to_be_bytes());
+  body.extend(cover.width.to_be_bytes());
+  body.extend(cover.height.to_be_bytes());
+  body.extend(cover.depth.to_be_bytes());
+  // Number of colors, only for indexed pictures such as GIF
+  body.extend(0u32.to_be_bytes());
+  body.extend((cover.data.len() as u32).to_be_bytes());
+  body.extend(&cover.data);
+  let mut block = block_header(last, 6, body.len()).to_vec();
+  block.extend(body);
+  block
+}
+
+/// Encodes interleaved f32 into FLAC frames, with fixed predictors and Rice coded residuals.
+pub struct FlacEncoder {
+  channels: usize,
+  bits: u8,
+  /// Samples of the block being filled, per channel. FLAC has the device channel order.
+  block: Vec<Vec<i64>>,
+  frame_number: u64,
+  total_samples: u64,
+  min_frame: usize,
+  max_frame: usize,
+  scratch: Vec<i64>,
+}
+
+impl FlacEncoder {
+  pub fn new(channels: usize, bits: u8) -> Self {
+    Self {
+      channels,
+      bits,
+      block: vec![Vec::with_capacity(BLOCK_LEN); channels],
+      frame_number: 0,
+      total_samples: 0,
+      min_frame: usize::MAX,
+      max_frame: 0,
+      scratch: Vec::with_capacity(BLOCK_LEN),
+    }
+  }
+
+  /// Buffers interleaved samples. `emit` receives every completed frame,
+  /// and the number of samples per channel encoded so far.
+  pub fn push(&mut self, pcm: &[f32], mut emit: impl FnMut(&[u8], u64) -> io::Result<()>) -> io::Result<()> {
+    for frame in pcm.chunks_exact(self.channels) {
+      for (ch, sample) in frame.iter().enumerate() {
+        self.block[ch].push(quantize(*sample, self.bits) as i64);
+      }
+      if self.block[0].len() == BLOCK_LEN {
+        let encoded = self.encode_frame();
+        emit(&encoded, self.total_samples)?;
+      }
+    }
+    Ok(())
+  }
+
+  /// Encodes what is left of the last block as a shorter frame.
+  pub fn flush(&mut self, mut emit: impl FnMut(&[u8], u64) -> io::Result<()>) -> io::Result<()> {
+    if self.block[0].is_empty() { return Ok(()) }
+    let encoded = self.encode_frame();
+    emit(&encoded, self.total_samples)
+  }
+
+  fn encode_frame(&mut self) -> Vec<u8> {
+    let len = self.block[0].len();
+    let mut w = BitWriter::new();
+    // Sync code, reserved bit, fixed blocksize stream
+    w.write(0xFFF8, 16);
+    // 4096 samples, or an explicit 16 bit size for the last frame
+    w.write(if len == BLOCK_LEN { 0b1100 } else { 0b0111 }, 4);
+    // 48kHz
+    w.write(0b1010, 4);
+    // Independent channels
+    w.write(self.channels as u64 - 1, 4);
+    w.write(if self.bits == 16 { 0b100 } else { 0b110 }, 3);
+    w.write(0, 1);
+    w.write_utf8(self.frame_number);
+    if len != BLOCK_LEN { w.write(len as u64 - 1, 16) }
+    let crc = crc8(&w.buf);
+    w.write(crc as u64, 8);
+
+    for ch in &self.block {
+      write_subframe(&mut w, ch, self.bits as u32, &mut self.scratch);
+    }
+    w.align();
+    let crc = crc16(&w.buf);
+    w.write(crc as u64, 16);
+
+    self.block.iter_mut().for_each(Vec::clear);
+    self.frame_number += 1;
+    self.total_samples += len as u64;
+    self.min_frame = self.min_frame.min(w.buf.len());
+    self.max_frame = self.max_frame.max(w.buf.len());
+    w.buf
+  }
+
+  /// STREAMINFO metadata block, including its header. Frame sizes and the sample count
+  /// are known once the stream is finished, before that they are written as unknown.
+  fn streaminfo(&self, last: bool) -> Vec<u8> {
+    let mut w = BitWriter::new();
+    w.buf.extend(block_header(last, 0, STREAMINFO_LEN));
+    w.write(BLOCK_LEN as u64, 16);
+    w.write(BLOCK_LEN as u64, 16);
+    w.write(if self.max_frame == 0 { 0 } else { self.min_frame as u64 }, 24);
+    w.write(self.max_frame as u64, 24);
+    w.write(DEFAULT_SR as u64, 20);
+    w.write(self.channels as u64 - 1, 3);
+    w.write(self.bits as u64 - 1, 5);
+    w.write(self.total_samples >> 32, 4);
+    w.write(self.total_samples, 32);
+    // MD5 of the audio, all zero for unknown
+    w.buf.extend([0; 16]);
+    w.buf
+  }
+}
+
+/// Native FLAC file. STREAMINFO is completed when the recording is finished,
+/// a file that was cut short still decodes.
+pub struct FlacWriter {
+  file: BufWriter<File>,
+  encoder: FlacEncoder,
+}
+
+impl FlacWriter {
+  pub fn create(
+    path: &Path,
+    tags: &[(&str, String)],
+    cover: Option<&Picture>,
+    channels: usize,
+    bits: u8,
+  ) -> io::Result<Self> {
+    let encoder = FlacEncoder::new(channels, bits);
+    let mut file = BufWriter::new(File::create(path)?);
+    file.write_all(b"fLaC")?;
+    file.write_all(&encoder.streaminfo(false))?;
+    file.write_all(&vorbis_comment(tags, cover.is_none()))?;
+    if let Some(cover) = cover {
+      file.write_all(&picture(cover, true))?;
+    }
+    Ok(Self { file, encoder })
+  }
+}
+
+impl Recorder for FlacWriter {
+  fn write(&mut self, pcm: &[f32]) -> anyhow::Result<()> {
+    let file = &mut self.file;
+    self.encoder.push(pcm, |frame, _| file.write_all(frame))?;
+    Ok(())
+  }
+
+  fn finish(&mut self) -> anyhow::Result<()> {
+    let file = &mut self.file;
+    self.encoder.flush(|frame, _| file.write_all(frame))?;
+    self.file.seek(SeekFrom::Start(STREAMINFO_OFFSET))?;
+    self.file.write_all(&self.encoder.streaminfo(false))?;
+    self.file.seek(SeekFrom::End(0))?;
+    self.file.flush()?;
+    Ok(())
+  }
+}
+
+/// FLAC in Ogg, one frame per packet. STREAMINFO can not be rewritten
+/// without recomputing the page checksum, so the sample count stays unknown.
+pub struct OggFlacWriter {
+  ogg: PacketWriter<BufWriter<File>>,
+  serial: u32,
+  encoder: FlacEncoder,
+  /// The latest frame is held back, so the last one can be flagged as the end of the stream.
+  pending: Option<(Vec<u8>, u64)>,
+}
+
+impl OggFlacWriter {
+  pub fn create(
+    path: &Path,
+    tags: &[(&str, String)],
+    cover: Option<&Picture>,
+    channels: usize,
+    bits: u8,
+  ) -> io::Result<Self> {
+    let encoder = FlacEncoder::new(channels, bits);
+    let mut ogg = PacketWriter::new(BufWriter::new(File::create(path)?));
+    let serial = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.subsec_nanos());
+
+    // Mapping header, version 1.0, followed by the other metadata blocks as header packets
+    let mut head = vec![0x7F];
+    head.extend(b"FLAC");
+    head.extend([1, 0]);
+    head.extend((1 + cover.is_some() as u16).to_be_bytes());
+    head.extend(b"fLaC");
+    head.extend(encoder.streaminfo(false));
+    ogg.write_packet(head.into_boxed_slice(), serial, PacketWriteEndInfo::EndPage, 0)?;
+    // VORBIS_COMMENT must be the first of them
+    let comment = vorbis_comment(tags, cover.is_none()).into_boxed_slice();
+    match cover {
+      Some(cover) => {
+        ogg.write_packet(comment, serial, PacketWriteEndInfo::NormalPacket, 0)?;
+        ogg.write_packet(picture(cover, true).into_boxed_slice(), serial, PacketWriteEndInfo::EndPage, 0)?;
+      }
+      None => ogg.write_packet(comment, serial, PacketWriteEndInfo::EndPage, 0)?,
+    }
+
+    Ok(Self { ogg, serial, encoder, pending: None })
+  }
+
+  fn write_pending(&mut self, end: PacketWriteEndInfo) -> io::Result<()> {
+    match self.pending.take() {
+      Some((frame, granule)) => self.ogg.write_packet(frame.into_boxed_slice(), self.serial, end, granule),
+      None => Ok(()),
+    }
+  }
+}
+
+impl Recorder for OggFlacWriter {
+  fn write(&mut self, pcm: &[f32]) -> anyhow::Result<()> {
+    let mut frames = Vec::new();
+    self.encoder.push(pcm, |frame, granule| {
+      frames.push((frame.to_vec(), granule));
+      Ok(())
+    })?;
+    for frame in frames {
+      self.write_pending(PacketWriteEndInfo::NormalPacket)?;
+      self.pending = Some(frame);
+    }
+    Ok(())
+  }
+
+  fn finish(&mut self) -> anyhow::Result<()> {
+    let mut last = None;
+    self.encoder.flush(|frame, granule| {
+      last = Some((frame.to_vec(), granule));
+      Ok(())
+    })?;
+    if let Some(frame) = last {
+      self.write_pending(PacketWriteEndInfo::NormalPacket)?;
+      self.pending = Some(frame);
+    }
+    self.write_pending(PacketWriteEndInfo::EndStream)?;
+    self.ogg.inner_mut().flush()?;
+    Ok(())
+  }
+}
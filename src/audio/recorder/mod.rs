@@ -0,0 +1,308 @@
+pub mod cue;
+pub mod flac;
+pub mod wav;
+
+use clap::ValueEnum;
+use opusenc::Encoder;
+use serde::{Deserialize, Serialize};
+
+use std::path::Path;
+
+use crate::{DEFAULT_SR, config::TauConfigError, metadata::{NowPlaying, StreamMetadata}};
+use super::{encoder::EncoderConfig, layout::VorbisOrder, mapping_family};
+use cue::CueChapters;
+use flac::{FlacWriter, OggFlacWriter};
+use wav::WavWriter;
+
+/// Default sample depth of the lossless formats.
+pub const DEFAULT_BIT_DEPTH: u8 = 24;
+
+/// Container and codec of the local recording, the live stream is always Ogg Opus.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum RecordingFormat {
+  /// Ogg Opus, the same as the stream
+  #[default]
+  Opus,
+  /// Native FLAC
+  Flac,
+  /// FLAC in an Ogg container
+  OggFlac,
+  /// WAV, switching to RF64 past 4 GB
+  Wav,
+}
+
+impl RecordingFormat {
+  pub fn is_default(&self) -> bool { *self == Self::default() }
+
+  pub fn extension(&self) -> &'static str {
+    match self {
+      RecordingFormat::Opus => "ogg",
+      RecordingFormat::Flac => "flac",
+      RecordingFormat::OggFlac => "oga",
+      RecordingFormat::Wav => "wav",
+    }
+  }
+}
+
+pub fn validate_bit_depth(bits: u8) -> Result<u8, TauConfigError> {
+  match bits {
+    16 | 24 => Ok(bits),
+    _ => Err(TauConfigError::Input(format!("recording_bit_depth must be 16 or 24, got {bits}"))),
+  }
+}
+
+/// Everything the recorder thread needs to know about the local recording.
+#[derive(Debug, Clone)]
+pub struct RecordingSettings {
+  pub format: RecordingFormat,
+  /// Sample depth of FLAC and WAV, Opus is always encoded from f32.
+  pub bit_depth: u8,
+  pub encoder: EncoderConfig,
+}
+
+/// Writes the local copy of the stream.
+pub trait Recorder {
+  /// Writes interleaved samples in the device channel order.
+  fn write(&mut self, pcm: &[f32]) -> anyhow::Result<()>;
+  /// Flushes buffered audio and completes the file's headers.
+  fn finish(&mut self) -> anyhow::Result<()>;
+  /// Tags what follows with `now`.
+  fn now_playing(&mut self, _now: &NowPlaying, _metadata: &StreamMetadata) -> anyhow::Result<()> { Ok(()) }
+}
+
+/// Ogg Opus recording, the encoder takes the surround channels in Vorbis order.
+pub struct OpusRecorder {
+  encoder: Encoder,
+  order: VorbisOrder,
+}
+
+impl Recorder for OpusRecorder {
+  fn write(&mut self, pcm: &[f32]) -> anyhow::Result<()> {
+    self.encoder.write_float(self.order.apply(pcm))?;
+    Ok(())
+  }
+
+  fn finish(&mut self) -> anyhow::Result<()> {
+    self.encoder.drain()?;
+    Ok(())
+  }
+
+  /// Ends the Ogg stream and chains a new one carrying the new tags. Players show each
+  /// chained stream as a track of its own, so in the file every one keeps the cover.
+  fn now_playing(&mut self, now: &NowPlaying, metadata: &StreamMetadata) -> anyhow::Result<()> {
+    self.encoder.chain_current(metadata.comments(Some(now), true)?)?;
+    Ok(())
+  }
+}
+
+/// Creates the recording at `path` in the configured format.
+pub fn create_recorder(
+  path: &Path,
+  metadata: &StreamMetadata,
+  channels: usize,
+  settings: &RecordingSettings,
+) -> anyhow::Result<Box<dyn Recorder>> {
+  let tags = metadata.tags(None);
+  let cover = metadata.cover();
+  Ok(match settings.format {
+    RecordingFormat::Opus => {
+      let mut encoder = Encoder::create_file(
+        path,
+        metadata.comments(None, true)?,
+        DEFAULT_SR,
+        channels,
+        mapping_family(channels),
+      )?;
+      settings.encoder.apply(&mut encoder)?;
+      Box::new(OpusRecorder { encoder, order: VorbisOrder::new(channels) })
+    }
+    RecordingFormat::Flac => Box::new(CueChapters::new(
+      FlacWriter::create(path, &tags, cover, channels, settings.bit_depth)?, path, &tags, channels,
+    )),
+    RecordingFormat::OggFlac => Box::new(CueChapters::new(
+      OggFlacWriter::create(path, &tags, cover, channels, settings.bit_depth)?, path, &tags, channels,
+    )),
+    RecordingFormat::Wav => Box::new(CueChapters::new(
+      WavWriter::create(path, &tags, channels, settings.bit_depth)?, path, &tags, channels,
+    )),
+  })
+}
+
+/// Quantizes a sample to a signed integer of `bits` bits.
+fn quantize(sample: f32, bits: u8) -> i32 {
+  let max = ((1i32 << (bits - 1)) - 1) as f32;
+  (sample.clamp(-1.0, 1.0) * max).round() as i32
+}
+
+#[cfg(test)]
+mod tests {
+  use symphonia::core::{
+    audio::{Channels, SampleBuffer},
+    codecs::DecoderOptions,
+    errors::Error as DecodeError,
+    formats::FormatOptions,
+    io::MediaSourceStream,
+    meta::MetadataOptions,
+    probe::Hint,
+  };
+
+  use std::{fs::File, io::ErrorKind, path::PathBuf};
+
+  use crate::metadata::Picture;
+  use super::*;
+
+  /// Frames written, not a multiple of the FLAC block length, so the last block is a short one.
+  const FRAMES: usize = 3 * 4096 + 1234;
+
+  fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("tau-radio-test-{}-{name}", std::process::id()))
+  }
+
+  /// A tone per channel with a little noise, a stretch of silence, and full scale samples
+  /// in both directions, to go through every kind of FLAC subframe.
+  fn signal(channels: usize) -> Vec<f32> {
+    let mut seed = 0x2545_F491u32;
+    let mut noise = move || {
+      seed ^= seed << 13;
+      seed ^= seed >> 17;
+      seed ^= seed << 5;
+      seed as f32 / u32::MAX as f32 - 0.5
+    };
+    (0..FRAMES).flat_map(|i| (0..channels).map(move |c| (i, c))).map(|(i, c)| match i {
+      0..4096 => {
+        let t = i as f32 / DEFAULT_SR as f32;
+        0.5 * (std::f32::consts::TAU * (220.0 + 110.0 * c as f32) * t).sin() + 0.01 * noise()
+      }
+      4096..8192 => 0.0,
+      _ if i % 2 == 0 => 1.0,
+      _ => -1.0,
+    }).collect()
+  }
+
+  /// The samples a recording of `pcm` should decode to.
+  fn expected(pcm: &[f32], bits: u8) -> Vec<i32> {
+    pcm.iter().map(|s| quantize(*s, bits)).collect()
+  }
+
+  /// Decodes a recording with symphonia, returning its samplerate, speaker positions and samples.
+  fn decode(path: &Path, bits: u8) -> (u32, Channels, Vec<i32>) {
+    let stream = MediaSourceStream::new(Box::new(File::open(path).unwrap()), Default::default());
+    let mut hint = Hint::new();
+    hint.with_extension(path.extension().unwrap().to_str().unwrap());
+    let mut format = symphonia::default::get_probe()
+      .format(&hint, stream, &FormatOptions::default(), &MetadataOptions::default())
+      .unwrap()
+      .format;
+    let track = format.default_track().unwrap();
+    let (id, rate) = (track.id, track.codec_params.sample_rate.unwrap());
+    let channels = track.codec_params.channels.unwrap();
+    let mut decoder = symphonia::default::get_codecs().make(&track.codec_params, &DecoderOptions::default()).unwrap();
+    let mut samples = Vec::new();
+    loop {
+      let packet = match format.next_packet() {
+        Ok(packet) => packet,
+        Err(DecodeError::IoError(e)) if e.kind() == ErrorKind::UnexpectedEof => break,
+        Err(e) => panic!("{e}"),
+      };
+      if packet.track_id() != id { continue; }
+      let decoded = decoder.decode(&packet).unwrap();
+      let mut buf = SampleBuffer::<i32>::new(decoded.capacity() as u64, *decoded.spec());
+      buf.copy_interleaved_ref(decoded);
+      // Decoded to the full range of i32
+      samples.extend(buf.samples().iter().map(|s| s >> (32 - bits)));
+    }
+    (rate, channels, samples)
+  }
+
+  /// Records `pcm` and decodes the recording again.
+  fn record(
+    format: RecordingFormat,
+    pcm: &[f32],
+    channels: usize,
+    bits: u8,
+    cover: Option<&Picture>,
+  ) -> (u32, Channels, Vec<i32>) {
+    let path = temp_path(&format!("{channels}ch-{bits}bit.{}", format.extension()));
+    let tags = [("TITLE", "round trip".to_string())];
+    let mut recorder: Box<dyn Recorder> = match format {
+      RecordingFormat::Flac => Box::new(FlacWriter::create(&path, &tags, cover, channels, bits).unwrap()),
+      RecordingFormat::OggFlac => Box::new(OggFlacWriter::create(&path, &tags, cover, channels, bits).unwrap()),
+      RecordingFormat::Wav => Box::new(WavWriter::create(&path, &tags, channels, bits).unwrap()),
+      RecordingFormat::Opus => unreachable!(),
+    };
+    // Written in uneven pieces, as they come from the capture
+    for chunk in pcm.chunks(1000 * channels) {
+      recorder.write(chunk).unwrap();
+    }
+    recorder.finish().unwrap();
+    drop(recorder);
+
+    let decoded = decode(&path, bits);
+    std::fs::remove_file(&path).unwrap();
+    decoded
+  }
+
+  fn round_trip(format: RecordingFormat, channels: usize, bits: u8, cover: Option<&Picture>) {
+    let pcm = signal(channels);
+    let (rate, decoded_channels, samples) = record(format, &pcm, channels, bits, cover);
+    assert_eq!(rate, DEFAULT_SR as u32);
+    assert_eq!(decoded_channels.count(), channels);
+    let expected = expected(&pcm, bits);
+    assert_eq!(samples.len(), expected.len());
+    if let Some(i) = samples.iter().zip(&expected).position(|(a, b)| a != b) {
+      panic!("sample {i} decodes to {}, expected {}", samples[i], expected[i]);
+    }
+  }
+
+  fn cover() -> Picture {
+    Picture { mime: "image/png", width: 1, height: 1, depth: 24, data: vec![0x89, b'P', b'N', b'G', 0, 1, 2, 3] }
+  }
+
+  #[test]
+  fn flac_round_trip() {
+    round_trip(RecordingFormat::Flac, 1, 16, None);
+    round_trip(RecordingFormat::Flac, 2, 16, None);
+    round_trip(RecordingFormat::Flac, 2, 24, Some(&cover()));
+    round_trip(RecordingFormat::Flac, 6, 24, None);
+  }
+
+  #[test]
+  fn ogg_flac_round_trip() {
+    round_trip(RecordingFormat::OggFlac, 2, 16, None);
+    round_trip(RecordingFormat::OggFlac, 2, 24, Some(&cover()));
+    round_trip(RecordingFormat::OggFlac, 6, 24, None);
+  }
+
+  #[test]
+  fn wav_round_trip() {
+    round_trip(RecordingFormat::Wav, 1, 16, None);
+    round_trip(RecordingFormat::Wav, 2, 16, None);
+    round_trip(RecordingFormat::Wav, 2, 24, None);
+    round_trip(RecordingFormat::Wav, 6, 24, None);
+  }
+
+  #[test]
+  fn keeps_speaker_positions_of_5_1() {
+    // A level per speaker, captured in device order: FL FR FC LFE BL BR
+    let levels = [0.1, 0.2, 0.3, 0.4, 0.5, 0.6];
+    let speakers = [
+      (Channels::FRONT_LEFT, 0.1),
+      (Channels::FRONT_RIGHT, 0.2),
+      (Channels::FRONT_CENTRE, 0.3),
+      (Channels::LFE1, 0.4),
+      (Channels::REAR_LEFT, 0.5),
+      (Channels::REAR_RIGHT, 0.6),
+    ];
+    let pcm: Vec<f32> = levels.repeat(4800);
+    for format in [RecordingFormat::Flac, RecordingFormat::OggFlac, RecordingFormat::Wav] {
+      let (_, channels, samples) = record(format, &pcm, 6, 16, None);
+      assert_eq!(channels, speakers.iter().fold(Channels::empty(), |all, (speaker, _)| all | *speaker));
+      // Decoded channels come in the order of their position bits
+      for (ch, position) in channels.iter().enumerate() {
+        let (_, level) = speakers.iter().find(|(speaker, _)| *speaker == position).unwrap();
+        assert_eq!(samples[ch], quantize(*level, 16), "{format:?} {position:?}");
+      }
+    }
+  }
+}
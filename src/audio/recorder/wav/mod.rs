@@ -0,0 +1,206 @@
+use std::{
+  fs::File,
+  io::{self, BufWriter, Seek, SeekFrom, Write},
+  path::Path,
+};
+
+use crate::DEFAULT_SR;
+use crate::audio::layout::channel_mask;
+use super::{Recorder, quantize};
+
+/// Size of the ds64 chunk body: RIFF size, data size, sample count and an empty table.
+const DS64_LEN: u32 = 28;
+/// Offset of the JUNK chunk that becomes ds64, right after `RIFF<size>WAVE`.
+const DS64_OFFSET: u64 = 12;
+/// Sizes in the header are brought up to date this often, so a recording cut short by a crash stays readable.
+const HEADER_UPDATE_BYTES: u64 = 16 * 1024 * 1024;
+/// RIFF INFO fields for the Vorbis comment fields that have one.
+const INFO_IDS: [(&str, &[u8; 4]); 5] = [
+  ("TITLE", b"INAM"),
+  ("ARTIST", b"IART"),
+  ("ALBUM", b"IPRD"),
+  ("GENRE", b"IGNR"),
+  ("DESCRIPTION", b"ICMT"),
+];
+const SOFTWARE: &str = concat!("tau-radio ", env!("CARGO_PKG_VERSION"));
+/// KSDATAFORMAT_SUBTYPE_PCM
+const PCM_GUID: [u8; 16] = [
+  0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x80, 0x00, 0x00, 0xAA, 0x00, 0x38, 0x9B, 0x71,
+];
+
+/// `LIST` chunk of INFO subchunks, including its header.
+fn info_list(tags: &[(&str, String)]) -> Vec<u8> {
+  let mut fields: Vec<(&[u8; 4], &str)> = tags
+    .iter()
+    .filter_map(|(key, value)| INFO_IDS.iter().find(|(k, _)| k == key).map(|(_, id)| (*id, value.as_str())))
+    .collect();
+  fields.push((b"ISFT", SOFTWARE));
+
+  let mut list = b"INFO".to_vec();
+  for (id, value) in fields {
+    // Zero terminated, and padded to an even length
+    let len = value.len() + 1;
+    list.extend(id);
+    list.extend((len as u32).to_le_bytes());
+    list.extend(value.as_bytes());
+    list.extend(if len.is_multiple_of(2) { &[0][..] } else { &[0, 0][..] });
+  }
+  let mut chunk = b"LIST".to_vec();
+  chunk.extend((list.len() as u32).to_le_bytes());
+  chunk.extend(list);
+  chunk
+}
+
+/// PCM WAV file, written with a placeholder chunk so it can be turned into RF64
+/// (EBU Tech 3306) once the data grows past what the 32 bit RIFF sizes can hold.
+pub struct WavWriter {
+  file: BufWriter<File>,
+  channels: usize,
+  bits: u8,
+  /// Offset of the data chunk's size field.
+  data_size_offset: u64,
+  data_len: u64,
+  last_update: u64,
+  buf: Vec<u8>,
+}
+
+impl WavWriter {
+  pub fn create(path: &Path, tags: &[(&str, String)], channels: usize, bits: u8) -> io::Result<Self> {
+    let mut file = BufWriter::new(File::create(path)?);
+    let block_align = channels as u16 * bits as u16 / 8;
+    // WAVE_FORMAT_PCM only covers mono and stereo up to 16 bit
+    let extensible = channels > 2 || bits > 16;
+
+    let mut fmt = Vec::with_capacity(40);
+    fmt.extend(if extensible { 0xFFFEu16 } else { 1 }.to_le_bytes());
+    fmt.extend((channels as u16).to_le_bytes());
+    fmt.extend((DEFAULT_SR as u32).to_le_bytes());
+    fmt.extend((DEFAULT_SR as u32 * block_align as u32).to_le_bytes());
+    fmt.extend(block_align.to_le_bytes());
+    fmt.extend((bits as u16).to_le_bytes());
+    if extensible {
+      fmt.extend(22u16.to_le_bytes());
+      fmt.extend((bits as u16).to_le_bytes());
+      fmt.extend(channel_mask(channels).to_le_bytes());
+      fmt.extend(PCM_GUID);
+    }
+
+    file.write_all(b"RIFF")?;
+    file.write_all(&0u32.to_le_bytes())?;
+    file.write_all(b"WAVE")?;
+    file.write_all(b"JUNK")?;
+    file.write_all(&DS64_LEN.to_le_bytes())?;
+    file.write_all(&[0; DS64_LEN as usize])?;
+    file.write_all(b"fmt ")?;
+    file.write_all(&(fmt.len() as u32).to_le_bytes())?;
+    file.write_all(&fmt)?;
+    let info = info_list(tags);
+    file.write_all(&info)?;
+    file.write_all(b"data")?;
+    file.write_all(&0u32.to_le_bytes())?;
+
+    let data_size_offset = DS64_OFFSET + 8 + DS64_LEN as u64 + 8 + fmt.len() as u64 + info.len() as u64 + 4;
+    Ok(Self { file, channels, bits, data_size_offset, data_len: 0, last_update: 0, buf: Vec::new() })
+  }
+
+  /// Writes the current sizes, as plain RIFF while they fit in 32 bits and as RF64 after that.
+  fn update_header(&mut self) -> io::Result<()> {
+    // Includes the pad byte of an odd sized data chunk
+    let riff_len = self.data_size_offset + 4 + self.data_len + self.data_len % 2 - 8;
+    if riff_len <= u32::MAX as u64 {
+      self.file.seek(SeekFrom::Start(4))?;
+      self.file.write_all(&(riff_len as u32).to_le_bytes())?;
+      self.file.seek(SeekFrom::Start(self.data_size_offset))?;
+      self.file.write_all(&(self.data_len as u32).to_le_bytes())?;
+    } else {
+      let block_align = (self.channels * self.bits as usize / 8) as u64;
+      self.file.seek(SeekFrom::Start(0))?;
+      self.file.write_all(b"RF64")?;
+      self.file.write_all(&u32::MAX.to_le_bytes())?;
+      self.file.seek(SeekFrom::Start(DS64_OFFSET))?;
+      self.file.write_all(b"ds64")?;
+      self.file.write_all(&DS64_LEN.to_le_bytes())?;
+      self.file.write_all(&riff_len.to_le_bytes())?;
+      self.file.write_all(&self.data_len.to_le_bytes())?;
+      self.file.write_all(&(self.data_len / block_align).to_le_bytes())?;
+      self.file.write_all(&0u32.to_le_bytes())?;
+      self.file.seek(SeekFrom::Start(self.data_size_offset))?;
+      self.file.write_all(&u32::MAX.to_le_bytes())?;
+    }
+    self.file.seek(SeekFrom::End(0))?;
+    self.last_update = self.data_len;
+    Ok(())
+  }
+}
+
+impl Recorder for WavWriter {
+  fn write(&mut self, pcm: &[f32]) -> anyhow::Result<()> {
+    let bytes = self.bits as usize / 8;
+    self.buf.clear();
+    for sample in &pcm[..pcm.len() - pcm.len() % self.channels] {
+      self.buf.extend_from_slice(&quantize(*sample, self.bits).to_le_bytes()[..bytes]);
+    }
+    self.file.write_all(&self.buf)?;
+    self.data_len += self.buf.len() as u64;
+    if self.data_len - self.last_update >= HEADER_UPDATE_BYTES {
+      self.update_header()?;
+    }
+    Ok(())
+  }
+
+  fn finish(&mut self) -> anyhow::Result<()> {
+    // Chunks are word aligned
+    if !self.data_len.is_multiple_of(2) {
+      self.file.write_all(&[0])?;
+    }
+    self.update_header()?;
+    self.file.flush()?;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn u32_at(bytes: &[u8], at: usize) -> u32 { u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap()) }
+  fn u64_at(bytes: &[u8], at: usize) -> u64 { u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap()) }
+
+  #[test]
+  fn switches_to_rf64_past_4_gb() {
+    let path = std::env::temp_dir().join(format!("tau-radio-test-{}-rf64.wav", std::process::id()));
+    let mut wav = WavWriter::create(&path, &[("TITLE", "rf64".to_string())], 2, 24).unwrap();
+    let offset = wav.data_size_offset as usize;
+
+    // Still fits in the 32 bit sizes
+    wav.data_len = 1200;
+    wav.update_header().unwrap();
+    wav.file.flush().unwrap();
+    let bytes = std::fs::read(&path).unwrap();
+    assert_eq!(&bytes[..4], b"RIFF");
+    assert_eq!(u32_at(&bytes, 4) as usize, offset + 4 + 1200 - 8);
+    assert_eq!(&bytes[12..16], b"JUNK");
+    assert_eq!(&bytes[offset - 4..offset], b"data");
+    assert_eq!(u32_at(&bytes, offset), 1200);
+
+    // The data is not written, only its size is needed for the header. Whole 6 byte frames, an even size
+    let data_len = 6 * (u32::MAX as u64 / 6 + 1);
+    wav.data_len = data_len;
+    wav.update_header().unwrap();
+    wav.file.flush().unwrap();
+    let bytes = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(&bytes[..4], b"RF64");
+    assert_eq!(u32_at(&bytes, 4), u32::MAX);
+    assert_eq!(&bytes[8..12], b"WAVE");
+    assert_eq!(&bytes[12..16], b"ds64");
+    assert_eq!(u32_at(&bytes, 16), DS64_LEN);
+    assert_eq!(u64_at(&bytes, 20), offset as u64 + 4 + data_len - 8);
+    assert_eq!(u64_at(&bytes, 28), data_len);
+    // 6 bytes per frame
+    assert_eq!(u64_at(&bytes, 36), data_len / 6);
+    assert_eq!(u32_at(&bytes, 44), 0);
+    assert_eq!(&bytes[48..52], b"fmt ");
+    assert_eq!(u32_at(&bytes, offset), u32::MAX);
+  }
+}
@@ -0,0 +1,79 @@
+use rubato::{
+  Resampler as _,
+  SincFixedIn,
+  SincInterpolationParameters,
+  SincInterpolationType,
+  WindowFunction,
+};
+
+/// Number of input frames handed to the sinc resampler per call.
+const CHUNK_LEN: usize = 480;
+
+/// Converts interleaved audio from a device's native samplerate to the stream samplerate.
+/// Buffers are allocated up front, so [`Resampler::process`] is safe to call from the audio callback.
+pub struct Resampler {
+  inner: SincFixedIn<f32>,
+  channels: usize,
+  input: Vec<Vec<f32>>,
+  output: Vec<Vec<f32>>,
+  interleaved: Vec<f32>,
+}
+
+impl Resampler {
+  /// `max_relative` bounds how far [`Resampler::set_ratio_relative`] may move the ratio, 1.0 for a fixed ratio.
+  pub fn new(from: u32, to: u32, channels: usize, max_relative: f64) -> anyhow::Result<Self> {
+    let params = SincInterpolationParameters {
+      sinc_len: 128,
+      f_cutoff: 0.95,
+      oversampling_factor: 128,
+      interpolation: SincInterpolationType::Linear,
+      window: WindowFunction::BlackmanHarris2,
+    };
+    let inner = SincFixedIn::<f32>::new(
+      to as f64 / from as f64,
+      max_relative,
+      params,
+      CHUNK_LEN,
+      channels,
+    )
+    .map_err(|e| anyhow::anyhow!("Could not create resampler from {from} Hz to {to} Hz: {e}"))?;
+    let out_len = inner.output_frames_max();
+    Ok(Self {
+      inner,
+      channels,
+      input: vec![Vec::with_capacity(CHUNK_LEN); channels],
+      output: vec![vec![0.0; out_len]; channels],
+      interleaved: Vec::with_capacity(out_len * channels),
+    })
+  }
+
+  /// Adjusts the ratio relative to the nominal `to / from`, to compensate for clock drift.
+  pub fn set_ratio_relative(&mut self, ratio: f64) {
+    if let Err(e) = self.inner.set_resample_ratio_relative(ratio, true) {
+      eprintln!("Could not adjust resampling ratio: {e}");
+    }
+  }
+
+  /// Accumulates interleaved samples, and hands resampled interleaved blocks to `emit`
+  /// each time a full chunk has been collected.
+  pub fn process(&mut self, buf: &[f32], mut emit: impl FnMut(&[f32])) {
+    for frame in buf.chunks_exact(self.channels) {
+      for (ch, sample) in frame.iter().enumerate() {
+        self.input[ch].push(*sample);
+      }
+      if self.input[0].len() < self.inner.input_frames_next() { continue; }
+
+      match self.inner.process_into_buffer(&self.input, &mut self.output, None) {
+        Ok((_, out_len)) => {
+          self.interleaved.clear();
+          for i in 0..out_len {
+            self.output.iter().for_each(|ch| self.interleaved.push(ch[i]));
+          }
+          emit(&self.interleaved);
+        }
+        Err(e) => eprintln!("Resampling error: {e}"),
+      }
+      self.input.iter_mut().for_each(Vec::clear);
+    }
+  }
+}
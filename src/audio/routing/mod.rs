@@ -0,0 +1,86 @@
+use serde::{Deserialize, Serialize};
+
+use crate::config::TauConfigError;
+
+/// Upper bound for device input channels, large enough for any audio interface in practice.
+pub const MAX_INPUT_CH: usize = 64;
+
+/// Sends one device input channel to one stream output channel.
+/// Channels are numbered from 1, as they are on the front panel of an audio interface.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Route {
+  pub input: usize,
+  pub output: usize,
+  #[serde(default = "unity_gain")]
+  pub gain: f32,
+}
+
+fn unity_gain() -> f32 { 1.0 }
+
+/// Parses a route from the CLI, formatted as `input:output[:gain]`, e.g. `3:1:0.7`
+pub fn parse_route(s: &str) -> Result<Route, TauConfigError> {
+  let parts: Vec<&str> = s.split(':').map(str::trim).collect();
+  let number = |p: &str| p.parse::<usize>()
+    .map_err(|e| TauConfigError::InvalidRoute(format!("{s}: {e}")));
+  match parts.as_slice() {
+    [input, output] => Ok(Route { input: number(input)?, output: number(output)?, gain: unity_gain() }),
+    [input, output, gain] => Ok(Route {
+      input: number(input)?,
+      output: number(output)?,
+      gain: gain.parse::<f32>().map_err(|e| TauConfigError::InvalidRoute(format!("{s}: {e}")))?,
+    }),
+    _ => Err(TauConfigError::InvalidRoute(format!("{s}: expected input:output[:gain]"))),
+  }
+}
+
+pub fn validate_input_channels(inputs: usize) -> Result<usize, TauConfigError> {
+  if !(1..=MAX_INPUT_CH).contains(&inputs) {
+    return Err(TauConfigError::InvalidRoute(
+      format!("input_channels must be between 1 and {MAX_INPUT_CH}, got {inputs}")
+    ));
+  }
+  Ok(inputs)
+}
+
+/// Mixes the interleaved device channels into the stream channels.
+pub struct ChannelMap {
+  inputs: usize,
+  outputs: usize,
+  /// Zero based (input, output, gain)
+  routes: Vec<(usize, usize, f32)>,
+}
+
+impl ChannelMap {
+  pub fn new(inputs: usize, outputs: usize, routes: &[Route]) -> Result<Self, TauConfigError> {
+    validate_input_channels(inputs)?;
+    let routes = routes
+      .iter()
+      .map(|r| {
+        if !(1..=inputs).contains(&r.input) {
+          return Err(TauConfigError::InvalidRoute(
+            format!("input {} is outside of the device's {inputs} input channels", r.input)
+          ));
+        }
+        if !(1..=outputs).contains(&r.output) {
+          return Err(TauConfigError::InvalidRoute(
+            format!("output {} is outside of the stream's {outputs} channels", r.output)
+          ));
+        }
+        Ok((r.input - 1, r.output - 1, r.gain))
+      })
+      .collect::<Result<Vec<_>, _>>()?;
+    Ok(Self { inputs, outputs, routes })
+  }
+
+  /// Writes the routed frames of `input` to `out`, replacing its content.
+  pub fn apply(&self, input: &[f32], out: &mut Vec<f32>) {
+    out.clear();
+    for frame in input.chunks_exact(self.inputs) {
+      let start = out.len();
+      out.resize(start + self.outputs, 0.0);
+      for (i, o, gain) in &self.routes {
+        out[start + o] += frame[*i] * gain;
+      }
+    }
+  }
+}
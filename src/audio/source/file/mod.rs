@@ -0,0 +1,84 @@
+use inline_colorization::*;
+use symphonia::core::{
+  audio::SampleBuffer,
+  codecs::{Decoder, DecoderOptions},
+  errors::Error as DecodeError,
+  formats::{FormatOptions, FormatReader},
+  io::MediaSourceStream,
+  meta::MetadataOptions,
+  probe::Hint,
+};
+
+use std::{fs::File, io::ErrorKind, path::Path};
+
+use super::Reader;
+
+/// Decodes the default track of a WAV, FLAC or Ogg Vorbis file.
+pub struct FileSource {
+  format: Box<dyn FormatReader>,
+  decoder: Box<dyn Decoder>,
+  track: u32,
+  samples: Option<SampleBuffer<f32>>,
+  pub rate: u32,
+  pub channels: usize,
+}
+
+impl FileSource {
+  pub fn open(path: &Path) -> anyhow::Result<Self> {
+    let unsupported = |e: &dyn std::fmt::Display| anyhow::anyhow!(
+      "{color_yellow}Could not play source file:{color_reset}\n\t{color_red}{}{color_reset}\n\n{e}",
+      path.display()
+    );
+    let file = File::open(path).map_err(|e| unsupported(&e))?;
+    let stream = MediaSourceStream::new(Box::new(file), Default::default());
+    let mut hint = Hint::new();
+    if let Some(ext) = path.extension().and_then(|e| e.to_str()) {
+      hint.with_extension(ext);
+    }
+    let probed = symphonia::default::get_probe()
+      .format(&hint, stream, &FormatOptions::default(), &MetadataOptions::default())
+      .map_err(|e| unsupported(&e))?;
+
+    let format = probed.format;
+    let track = format
+      .default_track()
+      .ok_or_else(|| unsupported(&"no audio track found"))?;
+    let params = &track.codec_params;
+    let rate = params.sample_rate.ok_or_else(|| unsupported(&"unknown samplerate"))?;
+    let channels = params.channels.map(|c| c.count()).ok_or_else(|| unsupported(&"unknown channel count"))?;
+    let decoder = symphonia::default::get_codecs()
+      .make(params, &DecoderOptions::default())
+      .map_err(|e| unsupported(&e))?;
+
+    Ok(Self { track: track.id, format, decoder, samples: None, rate, channels })
+  }
+}
+
+impl Reader for FileSource {
+  fn read(&mut self, buf: &mut Vec<f32>) -> anyhow::Result<bool> {
+    buf.clear();
+    loop {
+      let packet = match self.format.next_packet() {
+        Ok(packet) => packet,
+        Err(DecodeError::IoError(e)) if e.kind() == ErrorKind::UnexpectedEof => return Ok(false),
+        Err(e) => return Err(e.into()),
+      };
+      if packet.track_id() != self.track { continue; }
+
+      match self.decoder.decode(&packet) {
+        Ok(decoded) => {
+          let samples = match &mut self.samples {
+            Some(s) if s.capacity() >= decoded.capacity() * self.channels => s,
+            s => s.insert(SampleBuffer::new(decoded.capacity() as u64, *decoded.spec())),
+          };
+          samples.copy_interleaved_ref(decoded);
+          buf.extend_from_slice(samples.samples());
+          return Ok(true);
+        }
+        // A corrupt packet is skipped, like a dropout on a live input
+        Err(DecodeError::DecodeError(e)) => eprintln!("{color_yellow}Skipping undecodable packet:{color_reset} {e}"),
+        Err(e) => return Err(e.into()),
+      }
+    }
+  }
+}
@@ -0,0 +1,208 @@
+pub mod file;
+pub mod signal;
+pub mod stdin;
+
+use cpal::{SampleFormat, SampleRate, StreamConfig};
+use ringbuf::HeapProd;
+
+use std::{
+  path::PathBuf,
+  sync::{Arc, atomic::{AtomicBool, Ordering}},
+  thread::{sleep, spawn},
+  time::{Duration, Instant},
+};
+
+use crate::{
+  DEFAULT_SR,
+  audio::{
+    capture::{Capture, CaptureConfig},
+    layout::speakers,
+    routing::{ChannelMap, Route},
+  },
+  config::TauConfigError,
+};
+use file::FileSource;
+use signal::{SignalSource, TestSignal};
+use stdin::{RawPcm, StdinSource};
+
+/// How far a paced source may run ahead of real time. Keeps the ringbuffer from
+/// running dry when the reading thread is scheduled late.
+const LEAD: Duration = Duration::from_millis(200);
+/// How long to wait for the encoder to consume the last samples of an exhausted source.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Audio source used instead of an audio interface.
+pub enum Source {
+  /// A WAV, FLAC or Ogg Vorbis file, played once in real time.
+  File(PathBuf),
+  /// Raw interleaved PCM read from stdin.
+  Stdin(RawPcm),
+  /// A generated test signal, runs until the session is stopped.
+  Signal(TestSignal),
+}
+
+/// Reads blocks of interleaved f32 samples, at the source's own samplerate and channel count.
+trait Reader: Send {
+  /// Replaces the content of `buf` with the next block, returns `false` once the source is exhausted.
+  fn read(&mut self, buf: &mut Vec<f32>) -> anyhow::Result<bool>;
+}
+
+/// Keeps a source that can be read faster than real time at the pace of the stream.
+pub struct Pacer {
+  started: Instant,
+  rate: f64,
+  frames: u64,
+}
+
+impl Pacer {
+  pub fn new(rate: u32) -> Self {
+    Self { started: Instant::now(), rate: rate as f64, frames: 0 }
+  }
+
+  /// Counts `frames` as delivered, and sleeps while the source is more than [`LEAD`] ahead of the clock.
+  pub fn advance(&mut self, frames: usize) {
+    self.frames += frames as u64;
+    let due = Duration::from_secs_f64(self.frames as f64 / self.rate);
+    if let Some(ahead) = due.checked_sub(self.started.elapsed() + LEAD) {
+      sleep(ahead);
+    }
+  }
+}
+
+/// Adapts the channels of a source to the stream. Mono is copied to every channel, a mono stream
+/// gets the average of all channels. Otherwise each channel goes to the stream channel of the same
+/// speaker, and is dropped if the stream has none. Sources of more than 8 channels have no
+/// layout, their channels are passed one to one and surplus ones dropped.
+fn adapt_channels(inputs: usize, outputs: usize) -> Result<Option<ChannelMap>, TauConfigError> {
+  if inputs == outputs { return Ok(None) }
+  let routes: Vec<Route> = match (inputs, outputs, speakers(inputs), speakers(outputs)) {
+    (1, ..) => (1..=outputs).map(|output| Route { input: 1, output, gain: 1.0 }).collect(),
+    (_, 1, ..) => (1..=inputs).map(|input| Route { input, output: 1, gain: 1.0 / inputs as f32 }).collect(),
+    (_, _, Some(from), Some(to)) => from.iter().enumerate()
+      .filter_map(|(input, speaker)| to.iter().position(|s| s == speaker).map(|output| (input, output)))
+      .map(|(input, output)| Route { input: input + 1, output: output + 1, gain: 1.0 })
+      .collect(),
+    _ => (1..=inputs.min(outputs)).map(|ch| Route { input: ch, output: ch, gain: 1.0 }).collect(),
+  };
+  ChannelMap::new(inputs, outputs, &routes).map(Some)
+}
+
+/// Opens the source and starts feeding `tx` with the stream's channel count at [`DEFAULT_SR`].
+/// The session is shut down once the source is exhausted. Returns a name for the source.
+pub fn spawn_source(
+  source: Source,
+  channels: usize,
+  tx: HeapProd<f32>,
+  shutdown: Arc<AtomicBool>,
+) -> anyhow::Result<String> {
+  let (name, reader, rate, source_channels): (String, Box<dyn Reader>, u32, usize) = match source {
+    Source::File(path) => {
+      let file = FileSource::open(&path)?;
+      let (rate, source_channels) = (file.rate, file.channels);
+      (path.display().to_string(), Box::new(file), rate, source_channels)
+    }
+    Source::Stdin(pcm) => {
+      let source_channels = pcm.channels.unwrap_or(channels);
+      let name = format!("stdin ({}, {} Hz, {source_channels}ch)", pcm.format, pcm.rate);
+      (name, Box::new(StdinSource::new(pcm, source_channels)), pcm.rate, source_channels)
+    }
+    Source::Signal(signal) => {
+      (signal.to_string(), Box::new(SignalSource::new(signal, channels)), DEFAULT_SR as u32, channels)
+    }
+  };
+
+  let config = CaptureConfig {
+    stream: StreamConfig {
+      channels: source_channels as u16,
+      sample_rate: SampleRate(rate),
+      buffer_size: cpal::BufferSize::Default,
+    },
+    format: SampleFormat::F32,
+  };
+  let capture = Capture::new(tx, &config, adapt_channels(source_channels, channels)?, channels, 1.0, None)?;
+  if config.needs_resampling() {
+    crate::ui::print_resampling(&name, rate, DEFAULT_SR as u32);
+  }
+
+  let thread_name = name.clone();
+  spawn(move || {
+    if let Err(e) = pump(reader, capture, rate, source_channels, &shutdown) {
+      eprintln!("{thread_name}: {e}");
+    }
+    shutdown.store(true, Ordering::SeqCst);
+  });
+  Ok(name)
+}
+
+/// Pushes the source through the capture path in real time, until it is exhausted or the session shuts down.
+fn pump(
+  mut reader: Box<dyn Reader>,
+  mut capture: Capture,
+  rate: u32,
+  channels: usize,
+  shutdown: &AtomicBool,
+) -> anyhow::Result<()> {
+  let mut pacer = Pacer::new(rate);
+  let mut buf = Vec::with_capacity(8192);
+  while !shutdown.load(Ordering::SeqCst) {
+    if !reader.read(&mut buf)? {
+      // Let the encoder catch up with the tail before ending the session
+      let started = Instant::now();
+      while capture.buffered() > 0 && started.elapsed() < DRAIN_TIMEOUT {
+        sleep(Duration::from_millis(10));
+      }
+      break;
+    }
+    capture.push(&buf);
+    pacer.advance(buf.len() / channels);
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn route(inputs: usize, outputs: usize, frames: &[f32]) -> Vec<f32> {
+    let mut out = Vec::new();
+    adapt_channels(inputs, outputs).unwrap().unwrap().apply(frames, &mut out);
+    out
+  }
+
+  #[test]
+  fn adapts_channels() {
+    assert!(adapt_channels(2, 2).unwrap().is_none());
+    // Mono to every channel
+    assert_eq!(route(1, 2, &[0.5, -0.25]), [0.5, 0.5, -0.25, -0.25]);
+    assert_eq!(route(1, 4, &[0.5]), [0.5; 4]);
+    // Averaged down to mono
+    assert_eq!(route(2, 1, &[0.5, -0.25, 1.0, 1.0]), [0.125, 1.0]);
+    assert_eq!(route(4, 1, &[1.0, 0.5, 0.0, 0.5]), [0.5]);
+    // By speaker, those the stream lacks dropped or left silent
+    assert_eq!(route(4, 2, &[0.1, 0.2, 0.3, 0.4]), [0.1, 0.2]);
+    assert_eq!(route(2, 6, &[0.1, 0.2]), [0.1, 0.2, 0.0, 0.0, 0.0, 0.0]);
+    // 5.0 (FL FR FC BL BR) into 5.1 (FL FR FC LFE BL BR), the LFE stays silent
+    assert_eq!(route(5, 6, &[0.1, 0.2, 0.3, 0.4, 0.5]), [0.1, 0.2, 0.3, 0.0, 0.4, 0.5]);
+    // 5.1 into quad (FL FR BL BR), without centre and LFE
+    assert_eq!(route(6, 4, &[0.1, 0.2, 0.3, 0.4, 0.5, 0.6]), [0.1, 0.2, 0.5, 0.6]);
+    // 7.1 (FL FR FC LFE BL BR SL SR) into 5.1
+    assert_eq!(route(8, 6, &[0.1, 0.2, 0.3, 0.4, 0.5, 0.6, 0.7, 0.8]), [0.1, 0.2, 0.3, 0.4, 0.5, 0.6]);
+    // Without a layout, one to one
+    assert_eq!(route(10, 2, &[0.1, 0.2, 0.3, 0.4, 0.5, 0.6, 0.7, 0.8, 0.9, 1.0]), [0.1, 0.2]);
+  }
+
+  #[test]
+  fn paces_to_real_time() {
+    // 1000 frames a second, so a frame is a millisecond
+    let mut pacer = Pacer::new(1000);
+    let started = Instant::now();
+    // Within the lead, nothing to wait for
+    pacer.advance(LEAD.as_millis() as usize / 2);
+    assert!(started.elapsed() < LEAD / 2);
+    // Ahead of the clock by up to the lead, never more
+    pacer.advance(400);
+    let due = Duration::from_millis(400) + LEAD / 2;
+    assert!(started.elapsed() >= due - LEAD, "{:?}", started.elapsed());
+    assert!(started.elapsed() < due, "{:?}", started.elapsed());
+  }
+}
@@ -0,0 +1,129 @@
+use clap::ValueEnum;
+
+use std::{f64::consts::TAU, fmt};
+
+use crate::DEFAULT_SR;
+use super::Reader;
+
+/// Frames generated per block, 10ms at 48kHz.
+const BLOCK_FRAMES: usize = 480;
+/// -18 dBFS, the EBU alignment level.
+const LEVEL: f64 = 0.1259;
+/// Length of a pip in the channel ident, and of the silence between pips.
+const PIP_SECS: f64 = 0.12;
+/// Each channel is identified for this long, followed by the next one.
+const IDENT_SLOT_SECS: f64 = 2.0;
+/// Length of the sync beep, repeated every second.
+const BEEP_SECS: f64 = 0.05;
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum SignalKind {
+  /// Continuous sine tone on every channel, at -18 dBFS
+  Sine,
+  /// Pink noise, uncorrelated between channels
+  Pink,
+  /// Channel ident: channel 1 (left) beeps once, channel 2 (right) twice and so on, one after another
+  Ident,
+  /// Short beep on every channel at the start of every second, for checking latency and sync
+  Beep,
+}
+
+impl fmt::Display for SignalKind {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(match self {
+      SignalKind::Sine => "sine",
+      SignalKind::Pink => "pink noise",
+      SignalKind::Ident => "channel ident",
+      SignalKind::Beep => "sync beep",
+    })
+  }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TestSignal {
+  pub kind: SignalKind,
+  /// Frequency of the sine, the ident pips and the beep.
+  pub frequency: f64,
+}
+
+impl fmt::Display for TestSignal {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self.kind {
+      SignalKind::Pink => write!(f, "test signal ({})", self.kind),
+      kind => write!(f, "test signal ({kind}, {} Hz)", self.frequency),
+    }
+  }
+}
+
+/// Paul Kellet's economy pink noise filter, fed by a per channel xorshift generator.
+struct Pink {
+  state: u32,
+  b: [f64; 3],
+}
+
+impl Pink {
+  fn new(seed: u32) -> Self { Self { state: seed | 1, b: [0.0; 3] } }
+
+  fn next(&mut self) -> f64 {
+    self.state ^= self.state << 13;
+    self.state ^= self.state >> 17;
+    self.state ^= self.state << 5;
+    let white = self.state as f64 / u32::MAX as f64 * 2.0 - 1.0;
+    self.b[0] = 0.99765 * self.b[0] + white * 0.0990460;
+    self.b[1] = 0.96300 * self.b[1] + white * 0.2965164;
+    self.b[2] = 0.57000 * self.b[2] + white * 1.0526913;
+    // Brings the sum to roughly the same loudness as the sine
+    (self.b[0] + self.b[1] + self.b[2] + white * 0.1848) * LEVEL * 0.5
+  }
+}
+
+/// Generates the test signal at [`DEFAULT_SR`], in the stream's channel count.
+pub struct SignalSource {
+  signal: TestSignal,
+  channels: usize,
+  frame: u64,
+  pink: Vec<Pink>,
+}
+
+impl SignalSource {
+  pub fn new(signal: TestSignal, channels: usize) -> Self {
+    Self {
+      signal,
+      channels,
+      frame: 0,
+      pink: (0..channels as u32).map(|ch| Pink::new(0x9E37_79B9u32.wrapping_mul(ch + 1))).collect(),
+    }
+  }
+
+  /// Which channel the ident is on at `t` seconds, if it is sounding a pip.
+  fn ident_channel(&self, t: f64) -> Option<usize> {
+    let slot = t / IDENT_SLOT_SECS;
+    let channel = slot as usize % self.channels;
+    let pip = ((slot.fract() * IDENT_SLOT_SECS) / PIP_SECS) as usize;
+    // Pips on even steps, silence between them, `channel + 1` pips in total
+    (pip.is_multiple_of(2) && pip / 2 <= channel).then_some(channel)
+  }
+}
+
+impl Reader for SignalSource {
+  fn read(&mut self, buf: &mut Vec<f32>) -> anyhow::Result<bool> {
+    buf.clear();
+    let rate = DEFAULT_SR as f64;
+    for _ in 0..BLOCK_FRAMES {
+      let t = self.frame as f64 / rate;
+      let tone = (TAU * self.signal.frequency * t).sin() * LEVEL;
+      for ch in 0..self.channels {
+        let sample = match self.signal.kind {
+          SignalKind::Sine => tone,
+          SignalKind::Pink => self.pink[ch].next(),
+          SignalKind::Ident if self.ident_channel(t) == Some(ch) => tone,
+          SignalKind::Beep if t.fract() < BEEP_SECS => tone,
+          SignalKind::Ident | SignalKind::Beep => 0.0,
+        };
+        buf.push(sample as f32);
+      }
+      self.frame += 1;
+    }
+    Ok(true)
+  }
+}
@@ -0,0 +1,188 @@
+use clap::ValueEnum;
+
+use std::{
+  fmt,
+  io::{ErrorKind, Read, Stdin},
+};
+
+use super::Reader;
+
+/// Frames read from stdin per block, 10ms at 48kHz.
+const BLOCK_FRAMES: usize = 480;
+
+/// Sample encodings accepted on stdin, all little endian and interleaved.
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum PcmFormat {
+  S16le,
+  S24le,
+  S32le,
+  F32le,
+}
+
+impl PcmFormat {
+  fn bytes(self) -> usize {
+    match self {
+      PcmFormat::S16le => 2,
+      PcmFormat::S24le => 3,
+      PcmFormat::S32le | PcmFormat::F32le => 4,
+    }
+  }
+
+  fn decode(self, b: &[u8]) -> f32 {
+    match self {
+      PcmFormat::S16le => i16::from_le_bytes([b[0], b[1]]) as f32 / 32768.0,
+      // Shifted into the top of an i32, so the sign is extended on the way back down
+      PcmFormat::S24le => (i32::from_le_bytes([0, b[0], b[1], b[2]]) >> 8) as f32 / 8388608.0,
+      PcmFormat::S32le => i32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f32 / 2147483648.0,
+      PcmFormat::F32le => f32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+    }
+  }
+}
+
+impl fmt::Display for PcmFormat {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(match self {
+      PcmFormat::S16le => "s16le",
+      PcmFormat::S24le => "s24le",
+      PcmFormat::S32le => "s32le",
+      PcmFormat::F32le => "f32le",
+    })
+  }
+}
+
+/// Layout of the raw PCM piped to stdin, there is no header to read it from.
+#[derive(Debug, Clone, Copy)]
+pub struct RawPcm {
+  pub format: PcmFormat,
+  pub rate: u32,
+  /// Defaults to the stream's channel count.
+  pub channels: Option<usize>,
+}
+
+/// Reads raw PCM from stdin, e.g. `ffmpeg -i show.mp3 -f s16le -ar 48000 -ac 2 - | tau-radio --stdin`
+pub struct StdinSource<R = Stdin> {
+  input: R,
+  format: PcmFormat,
+  frame_bytes: usize,
+  buf: Vec<u8>,
+  /// Bytes of an incomplete frame, carried over to the next read.
+  pending: usize,
+}
+
+impl StdinSource {
+  pub fn new(pcm: RawPcm, channels: usize) -> Self {
+    Self::with_input(std::io::stdin(), pcm, channels)
+  }
+}
+
+impl<R: Read> StdinSource<R> {
+  fn with_input(input: R, pcm: RawPcm, channels: usize) -> Self {
+    let frame_bytes = pcm.format.bytes() * channels;
+    Self {
+      input,
+      format: pcm.format,
+      frame_bytes,
+      buf: vec![0; BLOCK_FRAMES * frame_bytes],
+      pending: 0,
+    }
+  }
+}
+
+impl<R: Read + Send> Reader for StdinSource<R> {
+  fn read(&mut self, buf: &mut Vec<f32>) -> anyhow::Result<bool> {
+    let filled = loop {
+      match self.input.read(&mut self.buf[self.pending..]) {
+        Ok(0) => return Ok(false),
+        Ok(n) if self.pending + n < self.frame_bytes => self.pending += n,
+        Ok(n) => break self.pending + n,
+        Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+        Err(e) => return Err(e.into()),
+      }
+    };
+    let whole = filled - filled % self.frame_bytes;
+
+    buf.clear();
+    let bytes = self.format.bytes();
+    buf.extend(self.buf[..whole].chunks_exact(bytes).map(|b| self.format.decode(b)));
+
+    self.buf.copy_within(whole..filled, 0);
+    self.pending = filled - whole;
+    Ok(true)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Hands out its bytes a few at a time, the way a pipe can.
+  struct Trickle<'a> {
+    bytes: &'a [u8],
+    step: usize,
+  }
+
+  impl Read for Trickle<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+      let n = self.step.min(buf.len()).min(self.bytes.len());
+      buf[..n].copy_from_slice(&self.bytes[..n]);
+      self.bytes = &self.bytes[n..];
+      Ok(n)
+    }
+  }
+
+  fn read_all(format: PcmFormat, channels: usize, bytes: &[u8], step: usize) -> Vec<f32> {
+    let pcm = RawPcm { format, rate: 48000, channels: Some(channels) };
+    let mut source = StdinSource::with_input(Trickle { bytes, step }, pcm, channels);
+    let (mut samples, mut buf) = (Vec::new(), Vec::new());
+    while source.read(&mut buf).unwrap() {
+      assert_eq!(buf.len() % channels, 0, "incomplete frame");
+      samples.extend(&buf);
+    }
+    samples
+  }
+
+  #[test]
+  fn decodes_sample_formats() {
+    let s16 = [0i16, 1, -1, i16::MAX, i16::MIN].iter().flat_map(|s| s.to_le_bytes()).collect::<Vec<_>>();
+    assert_eq!(
+      s16.chunks_exact(2).map(|b| PcmFormat::S16le.decode(b)).collect::<Vec<_>>(),
+      [0.0, 1.0 / 32768.0, -1.0 / 32768.0, 32767.0 / 32768.0, -1.0],
+    );
+
+    // The sign bit of 24 bit samples is the top bit of their third byte
+    let s24: [[u8; 3]; 5] = [[0, 0, 0], [1, 0, 0], [0xFF, 0xFF, 0xFF], [0xFF, 0xFF, 0x7F], [0, 0, 0x80]];
+    assert_eq!(
+      s24.iter().map(|b| PcmFormat::S24le.decode(b)).collect::<Vec<_>>(),
+      [0.0, 1.0 / 8388608.0, -1.0 / 8388608.0, 8388607.0 / 8388608.0, -1.0],
+    );
+
+    let s32 = [0i32, -1, i32::MIN, 1 << 30].iter().flat_map(|s| s.to_le_bytes()).collect::<Vec<_>>();
+    assert_eq!(
+      s32.chunks_exact(4).map(|b| PcmFormat::S32le.decode(b)).collect::<Vec<_>>(),
+      [0.0, -1.0 / 2147483648.0, -1.0, 0.5],
+    );
+
+    let f32 = [0.0f32, -0.25, 1.0, 1.5].iter().flat_map(|s| s.to_le_bytes()).collect::<Vec<_>>();
+    assert_eq!(
+      f32.chunks_exact(4).map(|b| PcmFormat::F32le.decode(b)).collect::<Vec<_>>(),
+      [0.0, -0.25, 1.0, 1.5],
+    );
+  }
+
+  #[test]
+  fn carries_incomplete_frames_over() {
+    let samples: Vec<i16> = (0..3000).map(|i| (i * 7 - 10000) as i16).collect();
+    let bytes: Vec<u8> = samples.iter().flat_map(|s| s.to_le_bytes()).collect();
+    let expected: Vec<f32> = samples.iter().map(|s| *s as f32 / 32768.0).collect();
+    // Reads that end in the middle of a sample and of a frame, and ones larger than a block
+    for step in [1, 5, 7, 4096, bytes.len()] {
+      assert_eq!(read_all(PcmFormat::S16le, 3, &bytes, step), expected, "{step} bytes per read");
+    }
+  }
+
+  #[test]
+  fn drops_a_trailing_incomplete_frame() {
+    let bytes: Vec<u8> = [0.5f32, -0.5, 0.25].iter().flat_map(|s| s.to_le_bytes()).collect();
+    assert_eq!(read_all(PcmFormat::F32le, 2, &bytes, 3), [0.5, -0.5]);
+  }
+}
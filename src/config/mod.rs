@@ -1,23 +1,121 @@
-use dialoguer::{Input, Password};
+use dialoguer::{Confirm, Input, Password, Select};
 use serde::{Deserialize, Serialize};
-use std::{fs, path::PathBuf};
+use std::{collections::BTreeMap, fs, path::{Path, PathBuf}};
+
+pub mod secret;
+use secret::SecretSource;
 
 use crate::{
   args::{
+    validate_channels,
     validate_port,
     validate_url_or_ip
   },
+  audio::{
+    devices::ChannelNeed,
+    encoder::EncoderConfig,
+    recorder::{DEFAULT_BIT_DEPTH, RecordingFormat, validate_bit_depth},
+    routing::{ChannelMap, Route},
+  },
+  metadata::StationMetadata,
+  threads::{
+    destination::{AuthScheme, Destination, StreamType},
+    keepalive::KeepaliveConfig,
+    reconnect::ReconnectConfig,
+    tls::TlsOptions,
+  },
+  util::consts::DEFAULT_CH,
 };
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Config {
+    /// `username` to `tls`, `protocol`, `path`, `auth`, `token` and the TLS options
+    /// describe the main server.
+    /// They may be left out when [`Destination`]s are listed instead.
+    #[serde(default)]
     pub username: String,
+    #[serde(default)]
     pub password: String,
+    /// Where the password is read from instead of `password`, see [`SecretSource`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password_source: Option<SecretSource>,
+    #[serde(default)]
     pub url: String,
+    #[serde(default)]
     pub upstream_port: u16,
-    pub audio_interface: String,
+    #[serde(with = "input_devices", default = "default_audio_interface")]
+    pub audio_interface: Vec<InputDevice>,
+    #[serde(default = "default_channels")]
+    pub channels: usize,
+    /// Number of channels to open the device with, when routing with [`Route`]s.
+    /// Defaults to the highest routed input.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub input_channels: Option<usize>,
     pub file: Option<String>,
-    pub tls: bool
+    /// Directory the recordings are saved to, `$HOME/tau/recordings` if unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recording_dir: Option<PathBuf>,
+    /// Format of the local recording, the stream is always Ogg Opus.
+    #[serde(default, skip_serializing_if = "RecordingFormat::is_default")]
+    pub recording_format: RecordingFormat,
+    /// Sample depth of FLAC and WAV recordings.
+    #[serde(default = "default_bit_depth", skip_serializing_if = "is_default_bit_depth")]
+    pub recording_bit_depth: u8,
+    #[serde(default)]
+    pub tls: bool,
+    /// CA file, certificate pin and client certificate of the main server.
+    #[serde(flatten)]
+    pub tls_options: TlsOptions,
+    /// WebSocket for tau-tower, or an Icecast source connection.
+    #[serde(default, skip_serializing_if = "StreamType::is_default")]
+    pub protocol: StreamType,
+    /// Path of the WebSocket endpoint, or the Icecast mount point.
+    #[serde(default, alias = "mount", skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auth: Option<AuthScheme>,
+    /// Sent with `auth = "bearer"`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token_source: Option<SecretSource>,
+    /// Proxy the servers are reached through, unless they set their own.
+    /// Defaults to `HTTPS_PROXY`, `HTTP_PROXY` or `ALL_PROXY`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proxy: Option<String>,
+    /// Servers streamed to along with the main one.
+    #[serde(default, rename = "destination", skip_serializing_if = "Vec::is_empty")]
+    pub destinations: Vec<Destination>,
+    /// Text file with the current track, watched for now playing updates.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata_file: Option<PathBuf>,
+    /// Socket accepting now playing updates, see [`crate::metadata::control`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub control_socket: Option<PathBuf>,
+    /// Device input to stream output routing, the device is passed through as is if empty.
+    #[serde(default, rename = "route", skip_serializing_if = "Vec::is_empty")]
+    pub routes: Vec<Route>,
+    /// Opus settings of the live stream.
+    #[serde(default, skip_serializing_if = "EncoderConfig::is_default")]
+    pub stream_encoder: EncoderConfig,
+    /// Opus settings of the local recording, independent of the stream's.
+    #[serde(default, skip_serializing_if = "EncoderConfig::is_default")]
+    pub record_encoder: EncoderConfig,
+    /// Tags of the stream and the recording.
+    #[serde(default, skip_serializing_if = "StationMetadata::is_default")]
+    pub metadata: StationMetadata,
+    /// Retry policy for the connection to the server.
+    #[serde(default, skip_serializing_if = "ReconnectConfig::is_default")]
+    pub reconnect: ReconnectConfig,
+    /// How a dead connection to the server is detected.
+    #[serde(default, skip_serializing_if = "KeepaliveConfig::is_default")]
+    pub keepalive: KeepaliveConfig,
+    /// The `[profile.<name>]` the settings were loaded with, if any.
+    #[serde(skip)]
+    pub profile: Option<String>,
+    /// The user's config.toml, the file settings picked at runtime are saved to.
+    #[serde(skip)]
+    pub config_path: PathBuf,
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -37,6 +135,33 @@ pub enum TauConfigError {
     #[error("invalid port number: {0}")]
     InvalidPort(String),
 
+    #[error("invalid channel count: {0}, expected 1 to 8")]
+    InvalidChannels(String),
+
+    #[error("invalid channel route: {0}")]
+    InvalidRoute(String),
+
+    #[error("invalid encoder setting: {0}")]
+    InvalidEncoder(String),
+
+    #[error("invalid reconnect setting: {0}")]
+    InvalidReconnect(String),
+
+    #[error("invalid keepalive setting: {0}")]
+    InvalidKeepalive(String),
+
+    #[error("invalid destination: {0}")]
+    InvalidDestination(String),
+
+    #[error("could not read credential: {0}")]
+    Credential(String),
+
+    #[error("{0}")]
+    Layered(#[from] config::ConfigError),
+
+    #[error("no profile '{0}' in config.toml, found: {1}")]
+    UnknownProfile(String, String),
+
     #[error("user input error: {0}")]
     Input(String),
 }
@@ -54,42 +179,166 @@ impl Config {
     }
   }
 
-  /// Merges local config.toml with current CLI arguments if there are any.
+  /// Merges the loaded settings with the CLI arguments, the last and strongest layer.
   pub fn merge_cli_args(mut self, args: &crate::args::Args) -> Self {
     if let Some(un) = &args.username {self.username = un.to_string()}
-    if let Some(pw) = &args.password {self.password = pw.to_string()}
+    if let Some(pw) = &args.password {
+      self.password = pw.to_string();
+      self.password_source = None;
+    }
+    if let Some(s)  = &args.password_from {self.password_source = Some(s.clone())}
     if let Some(u)  = &args.url      {self.url      = u.to_string()}
     if let Some(p)      = args.upstream_port      {self.upstream_port     = p}
+    if let Some(t)      = args.tls                {self.tls               = t}
+    if let Some(p)      = args.protocol           {self.protocol          = p}
+    if let Some(p)  = &args.path     {self.path     = Some(p.to_string())}
+    if let Some(a)      = args.auth               {self.auth              = Some(a)}
+    if let Some(t)  = &args.token    {
+      self.token = Some(t.to_string());
+      self.token_source = None;
+    }
+    if let Some(s)  = &args.token_from {self.token_source = Some(s.clone())}
+    if let Some(p)  = &args.proxy    {self.proxy    = Some(p.to_string())}
+    if !args.audio_interfaces.is_empty() {
+      self.audio_interface = args.audio_interfaces.iter().cloned().map(InputDevice::new).collect();
+    }
+    if let Some(c)      = args.channels           {self.channels          = c}
+    if let Some(c)      = args.input_channels     {self.input_channels    = Some(c)}
+    if !args.routes.is_empty() {self.routes = args.routes.clone()}
     if let Some(f)  = &args.file     {self.file     = Some(f.to_string())}
+    if let Some(d)  = &args.output   {self.recording_dir = Some(d.clone())}
+    if let Some(f)      = args.recording_format   {self.recording_format  = f}
+    if let Some(b)      = args.recording_bit_depth {self.recording_bit_depth = b}
+    if let Some(f)  = &args.metadata_file  {self.metadata_file  = Some(f.clone())}
+    if let Some(s)  = &args.control_socket {self.control_socket = Some(s.clone())}
+    self.stream_encoder.merge(&args.stream_encoder.to_config());
+    self.record_encoder.merge(&args.record_encoder.to_config());
+    args.reconnect.merge_into(&mut self.reconnect);
+    args.keepalive.merge_into(&mut self.keepalive);
+    args.metadata.merge_into(&mut self.metadata);
+    self.tls_options.merge(&args.tls_options.to_options());
     self
   }
 
-  fn load_config(path: &PathBuf) -> Result<Config, TauConfigError> {
-    let settings = fs::read_to_string(path)?; //.expect("could not read config file");
-    match toml::from_str(&settings) {
-      Ok(config) => Ok(config),
-      Err(e) => Err(TauConfigError::TomlDeserialize(e)),
+  /// The servers to stream to: the main server, if `url` is set, followed by the listed destinations.
+  /// Passwords and tokens kept elsewhere are read here.
+  pub fn destinations(&self) -> Result<Vec<Destination>, TauConfigError> {
+    let main = (!self.url.is_empty()).then(|| Destination {
+      protocol: self.protocol,
+      url: self.url.clone(),
+      port: self.upstream_port,
+      tls: self.tls,
+      path: self.path.clone(),
+      query: BTreeMap::new(),
+      headers: BTreeMap::new(),
+      auth: self.auth,
+      username: self.username.clone(),
+      password: self.password.clone(),
+      password_source: self.password_source.clone(),
+      token: self.token.clone(),
+      token_source: self.token_source.clone(),
+      tls_options: self.tls_options.clone(),
+      proxy: None,
+    });
+    let mut destinations: Vec<Destination> = main.into_iter()
+      .chain(self.destinations.iter().cloned())
+      .map(|d| Destination { proxy: d.proxy.or_else(|| self.proxy.clone()), ..d })
+      .collect();
+    if destinations.is_empty() {
+      return Err(TauConfigError::InvalidDestination("set url, or add a [[destination]] to stream to".to_string()));
+    }
+    for d in &mut destinations {
+      validate_url_or_ip(d.url.clone())?;
+      validate_port(d.port)?;
+      d.read_secrets()?;
+      d.validate()?;
     }
+    Ok(destinations)
   }
 
-  /// Creates an instance of Config, and reads from the saved `config.toml` file stored on disc.
-  /// If no `config.toml` file can be found, it prompts the user to enter one.
-  pub fn load_or_create(reset: bool) -> Result<Config, TauConfigError> {
-    let path = Self::get_config_path();
-    if path.exists() && !reset {
-      Self::load_config(&path)
+  /// Builds the mixing matrix from the device inputs to the stream channels,
+  /// or `None` if the device is passed through as is.
+  /// Routes set on the device itself take precedence over the top level ones.
+  pub fn channel_map(&self, device: &InputDevice, device_channels: usize) -> Result<Option<ChannelMap>, TauConfigError> {
+    let (input_channels, routes) = self.routing(device);
+    if routes.is_empty() {
+      return match input_channels {
+        Some(c) if c != self.channels => Err(TauConfigError::InvalidRoute(
+          format!("input_channels is {c}, but no routes to the stream's {} channels are set", self.channels)
+        )),
+        _ => Ok(None),
+      };
+    }
+    ChannelMap::new(device_channels, self.channels, routes).map(Some)
+  }
+
+  /// The channels `device` is opened with. Without `input_channels`, any count that covers
+  /// the highest routed input does, the capture picks the smallest one the device supports.
+  pub fn channel_need(&self, device: &InputDevice) -> ChannelNeed {
+    let (input_channels, routes) = self.routing(device);
+    need(input_channels, routes, self.channels)
+  }
+
+  /// The device's own `input_channels` and routes if it has routes, otherwise the top level ones.
+  fn routing<'a>(&'a self, device: &'a InputDevice) -> (Option<usize>, &'a [Route]) {
+    if device.routes.is_empty() {
+      (self.input_channels, &self.routes)
     } else {
-      config_not_found(&path);
+      (device.input_channels, &device.routes)
+    }
+  }
+
+  /// Reads the settings, each layer over the previous one: the defaults, the system's
+  /// config.toml, the user's one at `path`, and the `TAU_*` environment variables.
+  /// The settings of `profile`, from either file, go over the base ones.
+  fn load_config(path: &Path, profile: Option<&str>) -> Result<Config, TauConfigError> {
+    let mut document = toml::Table::new();
+    for file in [system_config_path(), Some(path.to_path_buf())].into_iter().flatten().filter(|f| f.exists()) {
+      let table = read_table(&file)?;
+      if holds_secrets(&table) && !secret::is_private(&file).unwrap_or(true) {
+        warn_about_permissions(&file);
+      }
+      merge_tables(&mut document, table);
+    }
+    let document = resolve_profile(document, profile)?;
+    let toml_string = toml::to_string(&document).map_err(TauConfigError::TomlSerialize)?;
+    let mut config: Config = config::Config::builder()
+      .add_source(config::File::from_str(&toml_string, config::FileFormat::Toml))
+      .add_source(environment())
+      .build()?
+      .try_deserialize()?;
+    config.profile = profile.map(str::to_string);
+    config.config_path = path.to_path_buf();
+    validate_channels(config.channels)?;
+    validate_bit_depth(config.recording_bit_depth)?;
+    config.stream_encoder.validate()?;
+    config.record_encoder.validate()?;
+    config.reconnect.validate()?;
+    config.keepalive.validate()?;
+    Ok(config)
+  }
+
+  /// Creates an instance of Config, and reads from the saved `config.toml` file stored on disc,
+  /// `path` if given. If there are no settings at all, in config.toml, the system's one or the
+  /// environment, or the file has no such `profile`, it prompts the user to enter them.
+  /// A new profile is added to the file, leaving the rest of it as it is.
+  pub fn load_or_create(reset: bool, path: Option<&Path>, profile: Option<&str>) -> Result<Config, TauConfigError> {
+    let path = path.map_or_else(Self::get_config_path, Path::to_path_buf);
+    let document = if path.exists() { read_table(&path)? } else { toml::Table::new() };
+    if Self::has_settings(&path, profile)? && !reset {
+      Self::load_config(&path, profile)
+    } else {
+      match profile {
+        Some(name) if path.exists() => profile_not_found(&path, name),
+        _ => config_not_found(&path),
+      }
       warn_about_credentials();
       let username: String = Input::new()
         .with_prompt(prompt("Username"))
         .interact_text()
         .map_err(|e| TauConfigError::Input(e.to_string()))?;
 
-      let password: String = Password::new()
-        .with_prompt(prompt("Password"))
-        .interact()
-        .map_err(|e| TauConfigError::Input(e.to_string()))?;
+      let (password, password_source) = prompt_password(&username)?;
       
       let url: String = Input::new()
         .with_prompt(prompt("Broadcast URL or IP"))
@@ -105,12 +354,19 @@ impl Config {
         .map_err(|e| TauConfigError::InvalidPort(e.to_string()))
         .and_then(validate_port)?;
 
-      let audio_interface = Input::new()
+      let audio_interface: String = Input::new()
         .with_prompt(prompt("Audio Interface"))
         .default(crate::DEFAULT_INPUT.to_string())
         .interact_text()
         .map_err(|e| TauConfigError::Input(e.to_string()))?;
 
+      let channels: usize = Input::new()
+        .with_prompt(prompt("Channels (1 = mono, 2 = stereo, up to 8 for surround)"))
+        .default(DEFAULT_CH)
+        .interact_text()
+        .map_err(|e| TauConfigError::InvalidChannels(e.to_string()))
+        .and_then(validate_channels)?;
+
       let file: String = Input::new()
         .with_prompt(prompt("Filename (leave empty for 'tau_[timestamp].ogg')"))
         .allow_empty(true)
@@ -127,10 +383,34 @@ impl Config {
       let config = Config {
         username,
         password,
+        password_source,
         url,
         upstream_port,
-        audio_interface,
+        audio_interface: vec![InputDevice::new(audio_interface)],
+        channels,
+        input_channels: None,
+        routes: Vec::new(),
+        recording_format: RecordingFormat::default(),
+        recording_bit_depth: DEFAULT_BIT_DEPTH,
+        stream_encoder: EncoderConfig::default(),
+        record_encoder: EncoderConfig::default(),
+        metadata_file: None,
+        control_socket: None,
+        metadata: StationMetadata::default(),
+        reconnect: ReconnectConfig::default(),
+        keepalive: KeepaliveConfig::default(),
+        profile: None,
         tls,
+        tls_options: TlsOptions::default(),
+        protocol: StreamType::default(),
+        path: None,
+        auth: None,
+        token: None,
+        token_source: None,
+        proxy: None,
+        destinations: Vec::new(),
+        recording_dir: None,
+        config_path: path.clone(),
         file: if file.trim().is_empty() { 
           None 
         } else { 
@@ -138,30 +418,355 @@ impl Config {
         },
       };
 
-      if let Some(parent) = path.parent() {
-        fs::create_dir_all(parent)?;
+      let mut document = document;
+      let answers = toml::Table::try_from(&config).map_err(TauConfigError::TomlSerialize)?;
+      match profile {
+        Some(name) => {
+          let profiles = document.entry(PROFILES).or_insert_with(|| toml::Table::new().into());
+          if let Some(profiles) = profiles.as_table_mut() {
+            profiles.insert(name.to_string(), answers.into());
+          }
+        }
+        // Replaces the base settings, keeping the profiles
+        None => {
+          let profiles = document.remove(PROFILES);
+          document = answers;
+          if let Some(profiles) = profiles { document.insert(PROFILES.to_string(), profiles); }
+        }
       }
-
-      let toml_string = toml::to_string_pretty(&config)
-        .map_err(|e| TauConfigError::TomlSerialize(e))?;
-      fs::write(&path, toml_string)?;
+      write_table(&path, &document)?;
       config_created(&path);
-      Ok(config)
+      Self::load_config(&path, profile)
+    }
+  }
+
+  /// Reads the settings like [`Config::load_or_create`], without asking for them when there are none.
+  pub fn load(path: Option<&Path>, profile: Option<&str>) -> Result<Option<Config>, TauConfigError> {
+    let path = path.map_or_else(Self::get_config_path, Path::to_path_buf);
+    if Self::has_settings(&path, profile)? {
+      Self::load_config(&path, profile).map(Some)
+    } else {
+      Ok(None)
+    }
+  }
+
+  /// Whether there are settings to load: a config.toml, the system's one or `TAU_*` variables,
+  /// or, with a `profile`, such a profile in either file.
+  fn has_settings(path: &Path, profile: Option<&str>) -> Result<bool, TauConfigError> {
+    let document = if path.exists() { Some(read_table(path)?) } else { None };
+    let system = system_config_path().filter(|p| p.exists()).map(|p| read_table(&p)).transpose()?;
+    Ok(match profile {
+      Some(name) => [document, system].iter().flatten().any(|d| profiles(d).is_some_and(|p| p.contains_key(name))),
+      None => document.is_some() || system.is_some() || environment_has_settings(),
+    })
+  }
+
+  /// The channels an audio interface needs to offer with the top level routing, for the device report.
+  pub fn device_channels(&self) -> ChannelNeed {
+    need(self.input_channels, &self.routes, self.channels)
+  }
+
+  /// Asks the user if the picked audio interface should replace the one in the user's `config.toml`.
+  /// Only the name of the `index`th `audio_interface` is rewritten, values passed as CLI arguments
+  /// or environment variables are not persisted. With a profile, it is saved in the profile.
+  pub fn offer_to_save_audio_interface(&self, index: usize, name: &str) -> Result<(), TauConfigError> {
+    let save = Confirm::new()
+      .with_prompt(prompt(&format!("Save '{name}' as audio_interface in config.toml?")))
+      .default(true)
+      .interact()
+      .map_err(|e| TauConfigError::Input(e.to_string()))?;
+    if !save { return Ok(()) }
+
+    let (path, profile) = (&self.config_path, self.profile.as_deref());
+    let mut document = if path.exists() { read_table(path)? } else { toml::Table::new() };
+    let mut config: Config = resolve_profile(document.clone(), profile)?
+      .try_into()
+      .map_err(TauConfigError::TomlDeserialize)?;
+    match config.audio_interface.get_mut(index) {
+      Some(device) => device.name = name.to_string(),
+      None => config.audio_interface.push(InputDevice::new(name.to_string())),
+    }
+    let devices = toml::Table::try_from(&config)
+      .map_err(TauConfigError::TomlSerialize)?
+      .remove("audio_interface");
+
+    let section = match profile {
+      Some(name) => profiles_mut(&mut document).and_then(|p| p.get_mut(name)).and_then(|p| p.as_table_mut()),
+      None => Some(&mut document),
+    };
+    if let (Some(section), Some(devices)) = (section, devices) {
+      section.insert("audio_interface".to_string(), devices);
+    }
+    write_table(path, &document)?;
+    config_created(path);
+    Ok(())
+  }
+}
+
+/// Routes without `input_channels` take any device with at least the highest routed input.
+fn need(input_channels: Option<usize>, routes: &[Route], channels: usize) -> ChannelNeed {
+  match (input_channels, routes.iter().map(|r| r.input).max()) {
+    (Some(inputs), Some(_)) => ChannelNeed { channels: inputs, at_least: false },
+    (None, Some(highest)) => ChannelNeed { channels: highest, at_least: true },
+    (_, None) => ChannelNeed { channels, at_least: false },
+  }
+}
+
+/// config.toml shared by every user, the lowest layer over the defaults.
+fn system_config_path() -> Option<PathBuf> {
+  cfg!(unix).then(|| PathBuf::from("/etc/tau/config.toml"))
+}
+
+/// Settings from `TAU_*` environment variables, e.g. `TAU_UPSTREAM_PORT=8443`,
+/// with `__` between the names of nested ones, e.g. `TAU_METADATA__GENRE=jazz`.
+fn environment() -> config::Environment {
+  config::Environment::with_prefix("TAU")
+    .prefix_separator("_")
+    .separator("__")
+    .ignore_empty(true)
+}
+
+/// Whether the environment changes any setting. `TAU_*` variables that are no
+/// setting, such as `TAU_CONFIG`, leave the settings as they are.
+fn environment_has_settings() -> bool {
+  changes_settings(environment())
+}
+
+/// Whether a variable of that name would be read as a setting, so it cannot hold a secret.
+fn is_setting_variable(name: &str) -> bool {
+  let vars = [(name.to_string(), "secret".to_string())].into_iter().collect();
+  changes_settings(environment().source(Some(vars)))
+}
+
+fn changes_settings(environment: config::Environment) -> bool {
+  let settings = |environment: Option<config::Environment>| -> Result<String, TauConfigError> {
+    let config: Config = config::Config::builder()
+      .add_source(environment.into_iter().collect::<Vec<_>>())
+      .build()?
+      .try_deserialize()?;
+    toml::to_string(&config).map_err(TauConfigError::TomlSerialize)
+  };
+  // An invalid value counts as a setting, so that loading reports it
+  match (settings(None), settings(Some(environment))) {
+    (Ok(defaults), Ok(environment)) => defaults != environment,
+    _ => true,
+  }
+}
+
+/// The table holding the `[profile.<name>]` sections of config.toml.
+const PROFILES: &str = "profile";
+
+fn read_table(path: &Path) -> Result<toml::Table, TauConfigError> {
+  let settings = fs::read_to_string(path)?;
+  toml::from_str(&settings).map_err(TauConfigError::TomlDeserialize)
+}
+
+fn write_table(path: &Path, table: &toml::Table) -> Result<(), TauConfigError> {
+  if let Some(parent) = path.parent() {
+    fs::create_dir_all(parent)?;
+  }
+  let toml_string = toml::to_string_pretty(table)
+    .map_err(TauConfigError::TomlSerialize)?;
+  // Readable by the owner only, as it may hold credentials
+  secret::write_private(path, &toml_string)?;
+  Ok(())
+}
+
+fn profiles(document: &toml::Table) -> Option<&toml::Table> {
+  document.get(PROFILES).and_then(|p| p.as_table())
+}
+
+fn profiles_mut(document: &mut toml::Table) -> Option<&mut toml::Table> {
+  document.get_mut(PROFILES).and_then(|p| p.as_table_mut())
+}
+
+/// The base settings of the file, with the ones of `profile` over them.
+fn resolve_profile(mut document: toml::Table, profile: Option<&str>) -> Result<toml::Table, TauConfigError> {
+  let profiles = document.remove(PROFILES);
+  let Some(name) = profile else { return Ok(document) };
+  let table = profiles.as_ref()
+    .and_then(|p| p.get(name))
+    .and_then(|p| p.as_table())
+    .ok_or_else(|| {
+      let names: Vec<&str> = profiles.as_ref().and_then(|p| p.as_table())
+        .map(|p| p.keys().map(String::as_str).collect())
+        .unwrap_or_default();
+      TauConfigError::UnknownProfile(name.to_string(), if names.is_empty() { "none".to_string() } else { names.join(", ") })
+    })?;
+  merge_tables(&mut document, table.clone());
+  Ok(document)
+}
+
+/// Tables are merged key by key, anything else in `over` replaces what is in `base`,
+/// so a profile can change a single `[metadata]` field, but lists such as
+/// `[[destination]]` are replaced as a whole.
+fn merge_tables(base: &mut toml::Table, over: toml::Table) {
+  for (key, value) in over {
+    match (base.get_mut(&key), value) {
+      (Some(toml::Value::Table(base)), toml::Value::Table(over)) => merge_tables(base, over),
+      (_, value) => { base.insert(key, value); }
     }
   }
 }
 
-pub struct Credentials {
-  username: String,
-  password: String,
+/// Whether a password or token is written in the file itself, in any section.
+fn holds_secrets(table: &toml::Table) -> bool {
+  fn value(key: &str, v: &toml::Value) -> bool {
+    match v {
+      toml::Value::String(s) => matches!(key, "password" | "token") && !s.is_empty(),
+      toml::Value::Table(t) => holds_secrets(t),
+      toml::Value::Array(a) => a.iter().any(|v| value(key, v)),
+      _ => false,
+    }
+  }
+  table.iter().any(|(k, v)| value(k, v))
 }
 
-impl Credentials {
-  pub fn new(username: String, password: String) -> Self {
-    Self { username, password }
+/// Asks where to keep the password. Returns the password to write into config.toml,
+/// empty when it is kept elsewhere, and its source.
+fn prompt_password(username: &str) -> Result<(String, Option<SecretSource>), TauConfigError> {
+  let input = |e: dialoguer::Error| TauConfigError::Input(e.to_string());
+  let ask_password = || Password::new().with_prompt(prompt("Password")).interact().map_err(input);
+  let choice = Select::new()
+    .with_prompt(prompt("Where should the password be kept?"))
+    .items(&[
+      "In config.toml (plain text)",
+      "In a file only you can read",
+      "In an environment variable",
+      "Printed by a command, e.g. `pass show tau`",
+      "In the keyring (freedesktop Secret Service)",
+    ])
+    .default(1)
+    .interact()
+    .map_err(input)?;
+
+  let source = match choice {
+    0 => return Ok((ask_password()?, None)),
+    1 => {
+      let default = Config::get_config_path().with_file_name("password");
+      let path: String = Input::new()
+        .with_prompt(prompt("Password file"))
+        .default(default.display().to_string())
+        .interact_text()
+        .map_err(input)?;
+      SecretSource::File(PathBuf::from(path))
+    }
+    // TAU_PASSWORD would be read as the `password` setting as well
+    2 => SecretSource::Env(
+      Input::new()
+        .with_prompt(prompt("Variable"))
+        .default("TAU_RADIO_PASSWORD".to_string())
+        .validate_with(|name: &String| match is_setting_variable(name) {
+          true => Err(format!("{name} is read as a setting, pick another name")),
+          false => Ok(()),
+        })
+        .interact_text()
+        .map_err(input)?
+    ),
+    3 => SecretSource::Command(Input::new().with_prompt(prompt("Command")).interact_text().map_err(input)?),
+    _ => SecretSource::Keyring(
+      Input::new().with_prompt(prompt("Service")).default("tau-radio".to_string()).interact_text().map_err(input)?
+    ),
+  };
+  if let SecretSource::File(path) = &source {
+    if let Some(parent) = path.parent() {
+      fs::create_dir_all(parent)?;
+    }
+    if !path.exists() {
+      source.store(username, &ask_password()?).map_err(TauConfigError::Credential)?;
+    }
+  } else if let SecretSource::Keyring(_) = &source {
+    source.store(username, &ask_password()?).map_err(TauConfigError::Credential)?;
+  }
+  Ok((String::new(), Some(source)))
+}
+
+fn default_audio_interface() -> Vec<InputDevice> { vec![InputDevice::new(crate::DEFAULT_INPUT.to_string())] }
+
+fn default_channels() -> usize { DEFAULT_CH }
+
+fn default_bit_depth() -> u8 { DEFAULT_BIT_DEPTH }
+
+fn is_default_bit_depth(bits: &u8) -> bool { *bits == DEFAULT_BIT_DEPTH }
+
+/// An audio interface to capture. Several of them are mixed into one stream.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct InputDevice {
+  pub name: String,
+  #[serde(default = "unity_gain")]
+  pub gain: f32,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub input_channels: Option<usize>,
+  #[serde(default, rename = "route", skip_serializing_if = "Vec::is_empty")]
+  pub routes: Vec<Route>,
+}
+
+impl InputDevice {
+  pub fn new(name: String) -> Self {
+    Self { name, gain: unity_gain(), input_channels: None, routes: Vec::new() }
+  }
+
+  /// Whether the device can be written back as a plain name.
+  fn is_plain(&self) -> bool {
+    self.gain == unity_gain() && self.input_channels.is_none() && self.routes.is_empty()
+  }
+}
+
+fn unity_gain() -> f32 { 1.0 }
+
+/// `audio_interface` is either a device name, or a list of device names and
+/// `{ name, gain, input_channels, route }` tables to mix several devices.
+mod input_devices {
+  use serde::{Deserialize, Deserializer, Serialize, Serializer};
+  use super::InputDevice;
+
+  #[derive(Serialize, Deserialize)]
+  #[serde(untagged)]
+  enum Entry {
+    Name(String),
+    Device(InputDevice),
+  }
+
+  impl From<Entry> for InputDevice {
+    fn from(entry: Entry) -> Self {
+      match entry {
+        Entry::Name(name) => InputDevice::new(name),
+        Entry::Device(device) => device,
+      }
+    }
+  }
+
+  impl From<&InputDevice> for Entry {
+    fn from(device: &InputDevice) -> Self {
+      if device.is_plain() { Entry::Name(device.name.clone()) } else { Entry::Device(device.clone()) }
+    }
+  }
+
+  #[derive(Serialize, Deserialize)]
+  #[serde(untagged)]
+  enum Devices {
+    One(Entry),
+    Many(Vec<Entry>),
+  }
+
+  pub fn serialize<S: Serializer>(devices: &[InputDevice], s: S) -> Result<S::Ok, S::Error> {
+    match devices {
+      [device] => Devices::One(device.into()),
+      _ => Devices::Many(devices.iter().map(Entry::from).collect()),
+    }
+    .serialize(s)
+  }
+
+  pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<InputDevice>, D::Error> {
+    let devices: Vec<InputDevice> = match Devices::deserialize(d)? {
+      Devices::One(entry) => vec![entry.into()],
+      Devices::Many(entries) => entries.into_iter().map(InputDevice::from).collect(),
+    };
+    if devices.is_empty() {
+      return Err(serde::de::Error::custom("audio_interface needs at least one device"));
+    }
+    Ok(devices)
   }
-  pub fn get_username(&self) -> String { self.username.clone() }
-  pub fn get_password(&self) -> String { self.password.clone() }
 }
 
 use inline_colorization::{
@@ -186,6 +791,78 @@ pub fn config_not_found(path: &PathBuf) {
   );
 }
 
+fn warn_about_permissions(path: &Path) {
+  println!(
+    "{color_bright_red}{} holds credentials and can be read by other users, \
+    restrict it with `chmod 600 {}`{color_reset}",
+    path.display(), path.display()
+  );
+}
+
+fn profile_not_found(path: &Path, name: &str) {
+  println!(
+    "\n{color_bright_yellow}No profile '{name}' in '{}'. Let's add one: {color_reset}",
+    path.display()
+  );
+}
+
 pub fn warn_about_credentials() {
   println!("{color_bright_red}Credentials must correspond to broadcast server stream config{color_reset}\n");
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// The settings of the `TAU_*` variables in `vars`, over the defaults.
+  fn from_environment(vars: &[(&str, &str)]) -> Result<Config, TauConfigError> {
+    let vars = vars.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+    Ok(config::Config::builder().add_source(environment().source(Some(vars))).build()?.try_deserialize()?)
+  }
+
+  #[test]
+  fn reads_typed_settings_from_the_environment() {
+    let config = from_environment(&[
+      ("TAU_USERNAME", "007"),
+      ("TAU_PASSWORD", "0123"),
+      ("TAU_UPSTREAM_PORT", "8443"),
+      ("TAU_CHANNELS", "6"),
+      ("TAU_TLS", "true"),
+      ("TAU_INSECURE_SKIP_VERIFY", "true"),
+      ("TAU_METADATA__GENRE", "1999"),
+      ("TAU_KEEPALIVE__TIMEOUT", "30"),
+      ("TAU_CONFIG", "/etc/tau/other.toml"),
+    ]).unwrap();
+    // Strings stay as they are, leading zeros included
+    assert_eq!(config.username, "007");
+    assert_eq!(config.password, "0123");
+    assert_eq!(config.metadata.genre.as_deref(), Some("1999"));
+    assert_eq!(config.upstream_port, 8443);
+    assert_eq!(config.channels, 6);
+    assert!(config.tls);
+    assert!(config.tls_options.insecure_skip_verify);
+    assert!(from_environment(&[("TAU_UPSTREAM_PORT", "port")]).is_err());
+  }
+
+  #[test]
+  fn tells_setting_variables_apart() {
+    assert!(is_setting_variable("TAU_PASSWORD"));
+    assert!(is_setting_variable("TAU_METADATA__GENRE"));
+    assert!(!is_setting_variable("TAU_RADIO_PASSWORD"));
+    assert!(!is_setting_variable("TAU_CONFIG"));
+    assert!(!is_setting_variable("RADIO_PASSWORD"));
+  }
+
+  #[test]
+  fn routes_need_the_highest_routed_input() {
+    let routes = [Route { input: 3, output: 1, gain: 1.0 }, Route { input: 6, output: 2, gain: 1.0 }];
+    let fields = |need: ChannelNeed| (need.channels, need.at_least);
+    assert_eq!(fields(need(None, &[], 2)), (2, false));
+    assert_eq!(fields(need(None, &routes, 2)), (6, true));
+    assert_eq!(fields(need(Some(8), &routes, 2)), (8, false));
+    // Opened at the fewest channels that cover the routes, never fewer
+    let need = need(None, &routes, 2);
+    assert!(!need.accepts(4));
+    assert!(need.accepts(6) && need.accepts(8));
+  }
+}
@@ -0,0 +1,159 @@
+use serde::{Deserialize, Serialize};
+
+use std::{
+  fmt::Display,
+  fs,
+  io::Write,
+  path::{Path, PathBuf},
+  process::{Command, Stdio},
+  str::FromStr,
+};
+#[cfg(unix)]
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+/// Where a password or token is read from when the session starts, so it does not
+/// have to be kept in config.toml or passed on the command line.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SecretSource {
+  /// A file holding only the secret, that only its owner can read
+  File(PathBuf),
+  /// An environment variable
+  Env(String),
+  /// A shell command printing the secret on its first line, e.g. `pass show tau`
+  Command(String),
+  /// The freedesktop Secret Service, looked up with `secret-tool` by service and username
+  Keyring(String),
+}
+
+impl SecretSource {
+  /// Reads the secret. `username` picks the entry in the Secret Service.
+  pub fn read(&self, username: &str) -> Result<String, String> {
+    let secret = match self {
+      Self::File(path) => {
+        if !is_private(path)? {
+          return Err(format!(
+            "{} can be read by other users, restrict it with `chmod 600 {}`", path.display(), path.display()
+          ));
+        }
+        let content = fs::read_to_string(path).map_err(|e| format!("could not read {}: {e}", path.display()))?;
+        content.trim_end_matches(['\r', '\n']).to_string()
+      }
+      Self::Env(name) => std::env::var(name).map_err(|_| format!("environment variable {name} is not set"))?,
+      Self::Command(command) => first_line(&mut shell(command), &format!("`{command}`"))?,
+      Self::Keyring(service) => first_line(
+        Command::new("secret-tool").args(["lookup", "service", service, "username", username]),
+        "secret-tool",
+      )?,
+    };
+    if secret.is_empty() {
+      return Err(match self {
+        Self::Keyring(service) => format!(
+          "no secret for service '{service}' and username '{username}' in the keyring, store one with \
+          `secret-tool store --label=tau-radio service {service} username {username}`"
+        ),
+        _ => format!("{self} is empty"),
+      });
+    }
+    Ok(secret)
+  }
+
+  /// Saves the secret where it is read from, for the sources that can hold one.
+  pub fn store(&self, username: &str, secret: &str) -> Result<(), String> {
+    match self {
+      Self::File(path) => write_private(path, &format!("{secret}\n")).map_err(|e| format!("{}: {e}", path.display())),
+      Self::Keyring(service) => {
+        let mut child = Command::new("secret-tool")
+          .args(["store", "--label=tau-radio", "service", service, "username", username])
+          .stdin(Stdio::piped())
+          .spawn()
+          .map_err(|e| format!("could not run secret-tool: {e}"))?;
+        child.stdin.take().map(|mut stdin| stdin.write_all(secret.as_bytes())).transpose().map_err(|e| e.to_string())?;
+        match child.wait() {
+          Ok(status) if status.success() => Ok(()),
+          Ok(status) => Err(format!("secret-tool store failed ({status})")),
+          Err(e) => Err(e.to_string()),
+        }
+      }
+      Self::Env(_) | Self::Command(_) => Ok(()),
+    }
+  }
+}
+
+impl Display for SecretSource {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Self::File(path) => write!(f, "file:{}", path.display()),
+      Self::Env(name) => write!(f, "env:{name}"),
+      Self::Command(command) => write!(f, "command:{command}"),
+      Self::Keyring(service) => write!(f, "keyring:{service}"),
+    }
+  }
+}
+
+/// `file:PATH`, `env:VAR`, `command:CMD` or `keyring:SERVICE`, as passed on the command line.
+impl FromStr for SecretSource {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let invalid = || format!("expected file:PATH, env:VAR, command:CMD or keyring:SERVICE, got '{s}'");
+    let (kind, value) = s.split_once(':').ok_or_else(invalid)?;
+    if value.is_empty() { return Err(invalid()); }
+    match kind {
+      "file" => Ok(Self::File(PathBuf::from(value))),
+      "env" => Ok(Self::Env(value.to_string())),
+      "command" => Ok(Self::Command(value.to_string())),
+      "keyring" => Ok(Self::Keyring(value.to_string())),
+      _ => Err(invalid()),
+    }
+  }
+}
+
+fn shell(command: &str) -> Command {
+  let (shell, flag) = if cfg!(windows) { ("cmd", "/C") } else { ("sh", "-c") };
+  let mut cmd = Command::new(shell);
+  cmd.arg(flag).arg(command);
+  cmd
+}
+
+/// Runs `command`, leaving stdin and stderr to the terminal for a passphrase prompt,
+/// and returns the first line it prints.
+fn first_line(command: &mut Command, name: &str) -> Result<String, String> {
+  let output = command
+    .stdin(Stdio::inherit())
+    .stderr(Stdio::inherit())
+    .output()
+    .map_err(|e| format!("could not run {name}: {e}"))?;
+  if !output.status.success() {
+    return Err(format!("{name} failed ({})", output.status));
+  }
+  let stdout = String::from_utf8(output.stdout).map_err(|_| format!("{name} printed something else than text"))?;
+  Ok(stdout.lines().next().unwrap_or_default().to_string())
+}
+
+/// Whether only the owner can read `path`. Always true where there are no Unix permissions.
+pub fn is_private(path: &Path) -> Result<bool, String> {
+  #[cfg(unix)]
+  {
+    let metadata = fs::metadata(path).map_err(|e| format!("could not read {}: {e}", path.display()))?;
+    Ok(metadata.permissions().mode() & 0o077 == 0)
+  }
+  #[cfg(not(unix))]
+  {
+    let _ = path;
+    Ok(true)
+  }
+}
+
+/// Writes `contents` to a file only the owner can read and write, also when it existed before.
+pub fn write_private(path: &Path, contents: &str) -> std::io::Result<()> {
+  let mut options = fs::OpenOptions::new();
+  options.write(true).create(true).truncate(true);
+  #[cfg(unix)]
+  options.mode(0o600);
+  let mut file = options.open(path)?;
+  // The mode is only applied to new files
+  #[cfg(unix)]
+  file.set_permissions(fs::Permissions::from_mode(0o600))?;
+  file.write_all(contents.as_bytes())
+}
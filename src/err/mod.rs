@@ -53,8 +53,8 @@ pub fn handle_input_build_error(err: BuildStreamError) -> anyhow::Error {
     BuildStreamError::StreamConfigNotSupported => anyhow::anyhow!(
       "StreamConfigNotSupported: \n\
       \tSome requirements for running Tau is not met by your audio source. \n\
-      \tCheck samplerate, it should be 48kHz.\
-      \n\tPlease adjust your system audio settings and try again."
+      \tCheck that it supports the configured number of channels,\
+      \n\tsee `tau-radio devices`. Please adjust your config or system audio settings and try again."
     ),
     BuildStreamError::InvalidArgument => {
       anyhow::anyhow!("Argument to underlying C-functions were not understood.")
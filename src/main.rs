@@ -3,48 +3,79 @@ mod args;
 mod audio;
 mod config;
 mod err;
+mod metadata;
 mod threads;
 mod ui;
 mod util;
 
-use crate::args::Args;
+use crate::args::{Args, Command};
+use crate::audio::{
+  open_input,
+  devices::ChannelNeed,
+  mixer::{DriftControl, MixerInput, mix_loop},
+  recorder::RecordingSettings,
+  source::spawn_source,
+};
 use crate::config::Config;
 use crate::err::AUDIO_INTERFACE_NOT_FOUND;
+use crate::metadata::{NowPlaying, StreamMetadata, control::{self, ControlSocket}, watch_file};
 use crate::threads::ws;
 use crate::util::create_recordings_dir;
 
 use clap::Parser;
-use cpal::{
-  SampleRate,
-  traits::{DeviceTrait, StreamTrait},
-  StreamConfig
-};
+use cpal::traits::StreamTrait;
 
 use inline_colorization::*;
 use ringbuf::{
   HeapRb,
-  traits::{Producer, Split},
+  traits::Split,
 };
 
 use std::{
   path::PathBuf,
+  process::exit,
   sync::{Arc, atomic::{AtomicBool, Ordering}},
-  thread::spawn
+  thread::spawn,
+  time::Instant,
 };
 
 use util::consts::{DEFAULT_CH, DEFAULT_SR, DEFAULT_INPUT};
-use config::Credentials;
 
 
 fn main() -> anyhow::Result<()> {
   let args = Args::parse();
-  let output = &args.output.clone();
-  let config = Config::load_or_create(args.reset_config).map(|c| c.merge_cli_args(&args))?;
-  let filename = crate::util::format_filename(config.file.clone());
-  let home = std::env::var("HOME")?;
-  let record_dir = match output {
-    Some(p) => PathBuf::from(p),
-    None => PathBuf::from(home).join("tau").join("recordings"),
+  match &args.command {
+    Some(Command::Devices { json }) => {
+      // Checked against the configured channels, or the ones passed with --channels
+      let config = Config::load(args.config.as_deref(), args.profile.as_deref())?.map(|c| c.merge_cli_args(&args));
+      let need = match &config {
+        Some(config) => config.device_channels(),
+        None => ChannelNeed { channels: args.channels.unwrap_or(DEFAULT_CH), at_least: false },
+      };
+      return crate::audio::devices::list(*json, need);
+    }
+    Some(Command::Metadata { artist, title, album, socket }) => {
+      let now = NowPlaying { artist: artist.clone(), title: title.clone(), album: album.clone() };
+      if now.is_empty() {
+        return Err(anyhow::anyhow!("Pass at least one of --artist, --title or --album"));
+      }
+      return control::send(&socket.clone().unwrap_or_else(control::default_path), &now);
+    }
+    None => {}
+  }
+  let mut config = Config::load_or_create(args.reset_config, args.config.as_deref(), args.profile.as_deref())
+    .map(|c| c.merge_cli_args(&args))?;
+  // CLI arguments can combine with the config into a policy that neither sets alone
+  config.reconnect.validate()?;
+  config.keepalive.validate()?;
+  let destinations = config.destinations()?;
+  destinations.iter()
+    .filter(|d| d.tls && d.tls_options.insecure_skip_verify)
+    .for_each(|d| crate::ui::print_insecure(&d.address()));
+  let filename = crate::util::format_filename(config.file.clone(), config.recording_format.extension());
+  let record_dir = match config.recording_dir.clone() {
+    Some(dir) => dir,
+    None => PathBuf::from(std::env::var("HOME")?).join("tau").join("recordings"),
   };
 
 
@@ -75,77 +106,112 @@ fn main() -> anyhow::Result<()> {
     ));
   }
 
-  let host = cpal::default_host();
-  let device = crate::audio::find_audio_device(&host, &config.audio_interface)?;
-  let (mut tx, rx) = HeapRb::<f32>::new(DEFAULT_SR as usize * 4).split();
+  let metadata = StreamMetadata::new(&filename, config.metadata.clone())?;
 
-  let creds: Credentials = Credentials::new(
-    config.username.clone(),
-    config.password.clone(),
-  );
+  let ring_len = DEFAULT_SR as usize * config.channels * 2;
+  let (mut tx, rx) = HeapRb::<f32>::new(ring_len).split();
+  let shutdown: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
+  let shutdown_clone = shutdown.clone();
+  // SIGINT and SIGTERM end the session cleanly, a second one does not wait for that
+  ctrlc::set_handler(move || {
+    if shutdown_clone.swap(true, Ordering::SeqCst) { exit(130); }
+  })?;
+
+  let (streams, source_name) = if let Some(source) = args.source() {
+    let name = spawn_source(source, config.channels, tx, shutdown.clone())?;
+    (Vec::new(), name)
+  } else if config.audio_interface.len() == 1 {
+    let host = cpal::default_host();
+    let stream = open_input(&host, &mut config, 0, tx, None)?;
+    (vec![stream], config.audio_interface[0].name.clone())
+  } else {
+    // Each device gets its own ringbuffer, which the mixer sums into the stream's
+    let host = cpal::default_host();
+    let mut streams = Vec::new();
+    let mut inputs = Vec::new();
+    for i in 0..config.audio_interface.len() {
+      let (device_tx, device_rx) = HeapRb::<f32>::new(ring_len).split();
+      let drift = (i > 0).then(DriftControl::new);
+      streams.push(open_input(&host, &mut config, i, device_tx, drift.clone())?);
+      inputs.push(MixerInput::new(device_rx, drift));
+    }
+    let shutdown_clone = shutdown.clone();
+    let channels = config.channels;
+    spawn(move || mix_loop(shutdown_clone, inputs, &mut tx, channels));
+    let names = config.audio_interface.iter().map(|d| d.name.as_str()).collect::<Vec<_>>().join(" + ");
+    (streams, names)
+  };
 
+  let socket_path = config.control_socket.clone().unwrap_or_else(control::default_path);
+  // Streaming goes on without live metadata if the socket cannot be opened
+  let _control_socket = ControlSocket::listen(&socket_path, metadata.clone())
+    .inspect_err(|e| eprintln!("{color_yellow}Could not open control socket:{color_reset} {e}"))
+    .ok();
+  if let Some(path) = config.metadata_file.clone() {
+    watch_file(path, metadata.clone(), shutdown.clone());
+  }
+
+  let addresses: Vec<String> = destinations.iter().map(|d| d.address()).collect();
   let filename = filename.clone();
-  let shutdown: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
   let shutdown_clone = shutdown.clone();
-  let url_clone = config.url.clone();
-  if args.no_recording {
+  let ws_thread = if args.no_recording {
     spawn(move ||
       ws::thread( 
         rx,
-        (&url_clone, config.upstream_port),
-        config.tls,
-        filename,
-        creds,
+        destinations,
+        config.channels,
+        config.stream_encoder,
+        metadata,
+        config.reconnect,
+        config.keepalive,
         shutdown_clone
       )
-    );
+    )
   } else {
     spawn(move || 
       ws::rec_thread(
         rx,
-        (&url_clone, config.upstream_port),
-        config.tls,
+        destinations,
+        config.channels,
+        config.stream_encoder,
+        RecordingSettings {
+          format: config.recording_format,
+          bit_depth: config.recording_bit_depth,
+          encoder: config.record_encoder,
+        },
         &record_dir,
         filename,
-        creds,
+        metadata,
+        config.reconnect,
+        config.keepalive,
         shutdown_clone
       )
-    );
-  }
-
-  let requested_config = StreamConfig {
-    channels: DEFAULT_CH as u16,
-    sample_rate: SampleRate(DEFAULT_SR as u32),
-    buffer_size: cpal::BufferSize::Default,
-  };
-
-  let stream = device
-    .build_input_stream(
-      &requested_config,
-      move |buf, _info| {
-        tx.push_slice(buf);
-      },
-      |e| {
-        eprintln!("{e}");
-        std::process::exit(1)
-      },
-      None,
     )
-    .map_err(crate::err::handle_input_build_error)?;
+  };
 
-  stream.play()?;
+  for stream in &streams {
+    stream.play()?;
+  }
 
   // Prints pretty message
   crate::ui::print_started_session_msg(
-    config.audio_interface,
+    source_name,
+    config.channels,
     &path,
     args.no_recording,
-    &config.url,
-    &config.upstream_port,
+    &addresses,
   );
 
-  loop {
-    if shutdown.load(Ordering::SeqCst) { return Ok(()) }
+  let started = Instant::now();
+  while !shutdown.load(Ordering::SeqCst) && !ws_thread.is_finished() {
     std::thread::sleep(std::time::Duration::from_millis(100));
   }
+  crate::ui::print_stopping(args.no_recording);
+  // Stops the capture loop, if the session ended on its own
+  shutdown.store(true, Ordering::SeqCst);
+  let stats = ws_thread.join()
+    .map_err(|_| anyhow::anyhow!("The streaming thread panicked"))?
+    .map_err(|e| anyhow::anyhow!("{e}"))?;
+  crate::ui::print_session_summary(started.elapsed(), &stats, (!args.no_recording).then_some(path.as_path()));
+  Ok(())
 }
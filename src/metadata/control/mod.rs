@@ -0,0 +1,179 @@
+use std::{
+  fs,
+  io::{self, BufRead, BufReader, ErrorKind, Write},
+  os::unix::{
+    fs::{DirBuilderExt, PermissionsExt},
+    net::{UnixListener, UnixStream},
+  },
+  path::{Path, PathBuf},
+  sync::Arc,
+  thread::spawn,
+  time::{SystemTime, UNIX_EPOCH},
+};
+
+use super::{NowPlaying, StreamMetadata};
+
+/// Local socket of a running session, `tau-radio metadata` and scripts write updates to it.
+/// The socket file is removed again when this is dropped.
+pub struct ControlSocket {
+  path: PathBuf,
+}
+
+impl ControlSocket {
+  /// Listens on `path` and publishes every update line written to it, see [`NowPlaying::parse`].
+  /// Each line is answered with `ok` or `error: <reason>`.
+  pub fn listen(path: &Path, metadata: Arc<StreamMetadata>) -> anyhow::Result<Self> {
+    if path.parent() == Some(fallback_dir().as_path()) {
+      private_dir(&fallback_dir())?;
+    }
+    if path.exists() {
+      if UnixStream::connect(path).is_ok() {
+        anyhow::bail!("{} is in use by another tau-radio session", path.display());
+      }
+      // Left behind by a session that did not exit cleanly
+      fs::remove_file(path)?;
+    }
+    let listener = bind_private(path)?;
+
+    spawn(move || {
+      for stream in listener.incoming().flatten() {
+        let metadata = metadata.clone();
+        spawn(move || handle_client(stream, &metadata));
+      }
+    });
+    Ok(Self { path: path.to_path_buf() })
+  }
+}
+
+impl Drop for ControlSocket {
+  fn drop(&mut self) {
+    let _ = fs::remove_file(&self.path);
+  }
+}
+
+/// Binds the socket in a new directory only the user can enter, so nobody can connect
+/// before its permissions are set, then moves it to `path`.
+fn bind_private(path: &Path) -> io::Result<UnixListener> {
+  let parent = path.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(Path::new("."));
+  let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.subsec_nanos());
+  let dir = parent.join(format!(".tau-radio-{}-{nanos}", std::process::id()));
+  fs::DirBuilder::new().mode(0o700).create(&dir)?;
+  let staged = dir.join("control.sock");
+  let bound = UnixListener::bind(&staged).and_then(|listener| {
+    // Only the user running the session may change what is on air
+    fs::set_permissions(&staged, fs::Permissions::from_mode(0o600))?;
+    fs::rename(&staged, path)?;
+    Ok(listener)
+  });
+  let _ = fs::remove_file(&staged);
+  let _ = fs::remove_dir(&dir);
+  bound
+}
+
+fn handle_client(stream: UnixStream, metadata: &StreamMetadata) {
+  let Ok(mut writer) = stream.try_clone() else { return };
+  for line in BufReader::new(stream).lines() {
+    let Ok(line) = line else { return };
+    if line.trim().is_empty() { continue; }
+    let reply = match NowPlaying::parse(&line) {
+      Some(now) => {
+        metadata.publish(now);
+        "ok".to_string()
+      }
+      None => format!("error: could not read an artist, title or album from '{line}'"),
+    };
+    if writeln!(writer, "{reply}").is_err() { return; }
+  }
+}
+
+/// Sends `now` to the session listening on `path`.
+pub fn send(path: &Path, now: &NowPlaying) -> anyhow::Result<()> {
+  if path.parent() == Some(fallback_dir().as_path()) {
+    private_dir(&fallback_dir())?;
+  }
+  let mut stream = UnixStream::connect(path).map_err(|e| {
+    anyhow::anyhow!("Could not connect to {}: {e}\nIs tau-radio running?", path.display())
+  })?;
+  writeln!(stream, "{}", serde_json::to_string(now)?)?;
+  let mut reply = String::new();
+  BufReader::new(stream).read_line(&mut reply)?;
+  match reply.trim() {
+    "ok" => Ok(()),
+    "" => anyhow::bail!("tau-radio closed the connection without answering"),
+    err => anyhow::bail!("{err}"),
+  }
+}
+
+/// `$XDG_RUNTIME_DIR/tau-radio.sock`, or a socket in a per-user directory in the temp directory.
+pub fn default_path() -> PathBuf {
+  match std::env::var("XDG_RUNTIME_DIR") {
+    Ok(dir) => PathBuf::from(dir).join("tau-radio.sock"),
+    Err(_) => fallback_dir().join("tau-radio.sock"),
+  }
+}
+
+/// Directory of the socket without `$XDG_RUNTIME_DIR`. Its name can be guessed by other users.
+fn fallback_dir() -> PathBuf {
+  let user = std::env::var("USER").unwrap_or_default();
+  std::env::temp_dir().join(format!("tau-radio-{user}"))
+}
+
+/// Creates `dir` for the user alone. One that is already there is refused if other users can
+/// enter it, as one of them may have created it. Another user's private directory is no risk,
+/// the socket can neither be bound nor reached in it.
+fn private_dir(dir: &Path) -> anyhow::Result<()> {
+  match fs::DirBuilder::new().mode(0o700).create(dir) {
+    Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+      let meta = fs::symlink_metadata(dir)?;
+      if !meta.is_dir() || meta.permissions().mode() & 0o077 != 0 {
+        anyhow::bail!("{} is open to other users, remove it or set control_socket", dir.display());
+      }
+      Ok(())
+    }
+    created => Ok(created?),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::os::unix::fs::FileTypeExt;
+
+  fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("tau-radio-test-{}-{name}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+  }
+
+  #[test]
+  fn binds_a_socket_only_the_user_can_use() {
+    let dir = temp_dir("control");
+    let path = dir.join("tau-radio.sock");
+    let _listener = bind_private(&path).unwrap();
+    let meta = fs::symlink_metadata(&path).unwrap();
+    assert!(meta.file_type().is_socket());
+    assert_eq!(meta.permissions().mode() & 0o777, 0o600);
+    UnixStream::connect(&path).unwrap();
+    // Nothing left of the directory it was bound in
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn refuses_directories_open_to_others() {
+    let dir = temp_dir("private");
+    let private = dir.join("private");
+    private_dir(&private).unwrap();
+    assert_eq!(fs::metadata(&private).unwrap().permissions().mode() & 0o777, 0o700);
+    private_dir(&private).unwrap();
+
+    let open = dir.join("open");
+    fs::create_dir(&open).unwrap();
+    fs::set_permissions(&open, fs::Permissions::from_mode(0o777)).unwrap();
+    assert!(private_dir(&open).is_err());
+    std::os::unix::fs::symlink(&private, dir.join("link")).unwrap();
+    assert!(private_dir(&dir.join("link")).is_err());
+    fs::remove_dir_all(&dir).unwrap();
+  }
+}
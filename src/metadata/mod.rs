@@ -0,0 +1,298 @@
+pub mod control;
+
+use crossbeam::channel::{Receiver, Sender, unbounded};
+use opusenc::{Comments, PictureType};
+use serde::{Deserialize, Serialize};
+
+use std::{
+  fmt::Display,
+  fs,
+  path::{Path, PathBuf},
+  sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}},
+  thread::{sleep, spawn},
+  time::Duration,
+};
+
+/// How often a `metadata_file` is checked for changes.
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+/// FLAC metadata blocks have a 24 bit length.
+const MAX_COVER_BYTES: usize = (1 << 24) - 64;
+
+/// The `[metadata]` table, tags that stay the same for the whole session.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct StationMetadata {
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub station: Option<String>,
+  /// Used as the title, instead of the recording's filename.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub show: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub artist: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub genre: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub description: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub language: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub url: Option<String>,
+  /// PNG or JPEG file, embedded as the front cover.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub cover: Option<PathBuf>,
+}
+
+impl StationMetadata {
+  pub fn is_default(&self) -> bool { *self == Self::default() }
+}
+
+/// Cover art, read once when the session starts.
+pub struct Picture {
+  pub mime: &'static str,
+  pub width: u32,
+  pub height: u32,
+  /// Bits per pixel.
+  pub depth: u32,
+  pub data: Vec<u8>,
+}
+
+impl Picture {
+  pub fn load(path: &Path) -> anyhow::Result<Picture> {
+    let data = fs::read(path)
+      .map_err(|e| anyhow::anyhow!("Could not read cover {}: {e}", path.display()))?;
+    if data.len() > MAX_COVER_BYTES {
+      anyhow::bail!("Cover {} is too large, it must be below 16 MB", path.display());
+    }
+    let (mime, (width, height, depth)) = if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+      ("image/png", png_size(&data))
+    } else if data.starts_with(&[0xFF, 0xD8]) {
+      ("image/jpeg", jpeg_size(&data))
+    } else {
+      anyhow::bail!("Cover {} is not a PNG or JPEG file", path.display())
+    };
+    Ok(Picture { mime, width, height, depth, data })
+  }
+}
+
+/// Width, height and bits per pixel from the IHDR chunk.
+fn png_size(data: &[u8]) -> (u32, u32, u32) {
+  let Some(ihdr) = data.get(16..26) else { return (0, 0, 0) };
+  let be32 = |b: &[u8]| u32::from_be_bytes([b[0], b[1], b[2], b[3]]);
+  let samples = match ihdr[9] {
+    // Greyscale with alpha, truecolor and truecolor with alpha
+    4 => 2,
+    2 => 3,
+    6 => 4,
+    // Greyscale, and palette entries which are looked up to 24 bit
+    3 => return (be32(&ihdr[0..4]), be32(&ihdr[4..8]), 24),
+    _ => 1,
+  };
+  (be32(&ihdr[0..4]), be32(&ihdr[4..8]), ihdr[8] as u32 * samples)
+}
+
+/// Width, height and bits per pixel from the first start of frame segment.
+fn jpeg_size(data: &[u8]) -> (u32, u32, u32) {
+  let mut i = 2;
+  while let Some(&[0xFF, marker, len_hi, len_lo]) = data.get(i..i + 4) {
+    let len = u16::from_be_bytes([len_hi, len_lo]) as usize;
+    // SOF0 to SOF15, except DHT, JPG and DAC which share the range
+    if (0xC0..=0xCF).contains(&marker) && ![0xC4, 0xC8, 0xCC].contains(&marker) {
+      let Some(sof) = data.get(i + 4..i + 10) else { break };
+      let height = u16::from_be_bytes([sof[1], sof[2]]) as u32;
+      let width = u16::from_be_bytes([sof[3], sof[4]]) as u32;
+      return (width, height, sof[0] as u32 * sof[5] as u32);
+    }
+    i += 2 + len;
+  }
+  (0, 0, 0)
+}
+
+/// What is on air right now, pushed while the stream is running.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct NowPlaying {
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub artist: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub title: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub album: Option<String>,
+}
+
+impl NowPlaying {
+  pub fn is_empty(&self) -> bool {
+    self.artist.is_none() && self.title.is_none() && self.album.is_none()
+  }
+
+  /// Parses an update, which is either
+  /// - a JSON object: `{"artist": "..", "title": "..", "album": ".."}`,
+  /// - `artist=..`, `title=..` and `album=..` lines, or
+  /// - a single `Artist - Title` line, as written by most playout software.
+  ///
+  /// Returns `None` if nothing could be read from `text`.
+  pub fn parse(text: &str) -> Option<NowPlaying> {
+    let text = text.trim();
+    if text.starts_with('{') {
+      return serde_json::from_str::<NowPlaying>(text).ok().filter(|n| !n.is_empty());
+    }
+    let mut now = NowPlaying::default();
+    for (key, value) in text.lines().filter_map(|l| l.split_once('=')) {
+      let value = Some(value.trim().to_string()).filter(|v| !v.is_empty());
+      match key.trim().to_lowercase().as_str() {
+        "artist" => now.artist = value,
+        "title" => now.title = value,
+        "album" => now.album = value,
+        _ => {}
+      }
+    }
+    if now.is_empty() {
+      let line = text.lines().next()?.trim();
+      now = match line.split_once(" - ") {
+        Some((artist, title)) => NowPlaying {
+          artist: Some(artist.trim().to_string()),
+          title: Some(title.trim().to_string()),
+          album: None,
+        },
+        None => NowPlaying { title: Some(line.to_string()).filter(|l| !l.is_empty()), ..Default::default() },
+      };
+    }
+    (!now.is_empty()).then_some(now)
+  }
+}
+
+impl Display for NowPlaying {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match (&self.artist, &self.title) {
+      (Some(artist), Some(title)) => write!(f, "{artist} - {title}")?,
+      (Some(one), None) | (None, Some(one)) => write!(f, "{one}")?,
+      (None, None) => {}
+    }
+    if let Some(album) = &self.album {
+      if self.artist.is_some() || self.title.is_some() {
+        write!(f, " ")?;
+      }
+      write!(f, "({album})")?;
+    }
+    Ok(())
+  }
+}
+
+/// The tags of the stream and the recording: the fixed ones from `[metadata]`,
+/// followed by what is playing now. Hands every now playing update to the encoder
+/// and recorder threads. A late subscriber starts with the latest update, so
+/// nothing is lost while the threads start up.
+pub struct StreamMetadata {
+  /// Title when neither `show` nor an update sets one, the recording's filename.
+  title: String,
+  station: StationMetadata,
+  cover: Option<Picture>,
+  current: Mutex<Option<NowPlaying>>,
+  subscribers: Mutex<Vec<Sender<NowPlaying>>>,
+}
+
+impl StreamMetadata {
+  pub fn new(title: &str, station: StationMetadata) -> anyhow::Result<Arc<Self>> {
+    let cover = station.cover.as_deref().map(Picture::load).transpose()?;
+    Ok(Arc::new(Self {
+      title: title.to_string(),
+      station,
+      cover,
+      current: Mutex::new(None),
+      subscribers: Mutex::new(Vec::new()),
+    }))
+  }
+
+  pub fn cover(&self) -> Option<&Picture> { self.cover.as_ref() }
+
+  pub fn station(&self) -> &StationMetadata { &self.station }
+
+  /// Name of the station for directories and players: the station, the show or the filename.
+  pub fn name(&self) -> &str {
+    self.station.station.as_deref().or(self.station.show.as_deref()).unwrap_or(&self.title)
+  }
+
+  /// Vorbis comment fields, for the stream with `now` on air.
+  pub fn tags(&self, now: Option<&NowPlaying>) -> Vec<(&'static str, String)> {
+    let station = &self.station;
+    let title = now
+      .and_then(|n| n.title.clone())
+      .or_else(|| station.show.clone())
+      .unwrap_or_else(|| self.title.clone());
+    let artist = now.and_then(|n| n.artist.clone()).or_else(|| station.artist.clone());
+    let album = now.and_then(|n| n.album.clone());
+
+    let mut tags = vec![("TITLE", title)];
+    let optional = [
+      ("ARTIST", artist),
+      ("ALBUM", album),
+      ("ORGANIZATION", station.station.clone()),
+      ("GENRE", station.genre.clone()),
+      ("DESCRIPTION", station.description.clone()),
+      ("LANGUAGE", station.language.clone()),
+      ("CONTACT", station.url.clone()),
+    ];
+    tags.extend(optional.into_iter().filter_map(|(key, value)| value.map(|v| (key, v))));
+    tags
+  }
+
+  /// Builds the Ogg comments of a (chained) Opus stream. The cover can take up to
+  /// 16 MB, it is left out of the streams chained for now playing updates on air.
+  pub fn comments(&self, now: Option<&NowPlaying>, with_cover: bool) -> anyhow::Result<Comments> {
+    let mut comments = Comments::create();
+    for (key, value) in self.tags(now) {
+      comments.add_string(format!("{key}={value}"))?;
+    }
+    if let Some(cover) = self.cover.as_ref().filter(|_| with_cover) {
+      comments.add_picture_from_memory(&cover.data, PictureType::FrontCover, None::<String>)?;
+    }
+    Ok(comments)
+  }
+
+  pub fn subscribe(&self) -> Receiver<NowPlaying> {
+    let (tx, rx) = unbounded();
+    if let Some(now) = self.current.lock().unwrap_or_else(|e| e.into_inner()).clone() {
+      let _ = tx.send(now);
+    }
+    self.subscribers.lock().unwrap_or_else(|e| e.into_inner()).push(tx);
+    rx
+  }
+
+  pub fn publish(&self, now: NowPlaying) {
+    crate::ui::print_now_playing(&now);
+    *self.current.lock().unwrap_or_else(|e| e.into_inner()) = Some(now.clone());
+    // Threads that have finished dropped their receiver
+    self.subscribers
+      .lock()
+      .unwrap_or_else(|e| e.into_inner())
+      .retain(|tx| tx.send(now.clone()).is_ok());
+  }
+}
+
+/// Polls `path` and publishes its contents whenever they change, for playout
+/// software that writes the current track to a text file.
+pub fn watch_file(path: PathBuf, metadata: Arc<StreamMetadata>, shutdown: Arc<AtomicBool>) {
+  spawn(move || {
+    let mut last = String::new();
+    let mut missing = false;
+    loop {
+      if shutdown.load(Ordering::SeqCst) { break; }
+      match fs::read_to_string(&path) {
+        Ok(text) => {
+          missing = false;
+          if text != last {
+            if let Some(now) = NowPlaying::parse(&text) {
+              metadata.publish(now);
+            }
+            last = text;
+          }
+        }
+        Err(e) if !missing => {
+          eprintln!("Could not read metadata file {}: {e}", path.display());
+          missing = true;
+        }
+        Err(_) => {}
+      }
+      sleep(WATCH_INTERVAL);
+    }
+  });
+}
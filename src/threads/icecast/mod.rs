@@ -0,0 +1,149 @@
+use tungstenite::stream::MaybeTlsStream;
+
+use std::{
+  io::{ErrorKind, Read, Write},
+  net::TcpStream,
+};
+
+use crate::{audio::encoder::EncoderConfig, metadata::StreamMetadata, util::consts::DEFAULT_SR};
+use super::{
+  destination::Destination,
+  keepalive::{KeepaliveConfig, set_read_poll},
+  sink::Sink,
+  transport::Transport,
+};
+
+/// Longest response header accepted from the server.
+const MAX_RESPONSE: usize = 8192;
+
+/// Builds the request that opens a source connection on the destination's mount point.
+/// Icecast takes the now playing updates from the chained Ogg streams themselves.
+pub fn request(
+  destination: &Destination,
+  metadata: &StreamMetadata,
+  channels: usize,
+  encoder: &EncoderConfig,
+) -> String {
+  let station = metadata.station();
+  let mut audio_info = format!("channels={channels};samplerate={DEFAULT_SR}");
+  if let Some(kbps) = encoder.bitrate {
+    audio_info.push_str(&format!(";bitrate={kbps}"));
+  }
+
+  let mut head = format!(
+    "PUT {} HTTP/1.1\r\n\
+    Host: {}\r\n\
+    User-Agent: tau-radio/{}\r\n\
+    Content-Type: audio/ogg\r\n\
+    Expect: 100-continue\r\n\
+    Ice-Public: 0\r\n\
+    Ice-Name: {}\r\n\
+    Ice-Audio-Info: {audio_info}\r\n",
+    destination.path_and_query(),
+    destination.address(),
+    env!("CARGO_PKG_VERSION"),
+    header_value(metadata.name()),
+  );
+  let optional = [
+    ("Ice-Description", &station.description),
+    ("Ice-Genre", &station.genre),
+    ("Ice-Url", &station.url),
+  ];
+  for (header, value) in optional {
+    if let Some(value) = value {
+      head.push_str(&format!("{header}: {}\r\n", header_value(value)));
+    }
+  }
+  if let Some(kbps) = encoder.bitrate {
+    head.push_str(&format!("Ice-Bitrate: {kbps}\r\n"));
+  }
+  for (name, value) in destination.request_headers() {
+    head.push_str(&format!("{name}: {}\r\n", header_value(&value)));
+  }
+  head.push_str("\r\n");
+  head
+}
+
+/// Header values end at a line break.
+fn header_value(value: &str) -> String {
+  value.replace(['\r', '\n'], " ")
+}
+
+/// A source connection to an Icecast server. The stream is the body of an HTTP PUT
+/// request, which lasts as long as the connection.
+pub struct IcecastSink {
+  stream: MaybeTlsStream<TcpStream>,
+}
+
+impl IcecastSink {
+  pub fn connect(
+    request: &str,
+    destination: &Destination,
+    keepalive: &KeepaliveConfig,
+    transport: &Transport,
+  ) -> Result<Self, String> {
+    let mut stream = transport.connect(destination, keepalive)?;
+    stream.write_all(request.as_bytes()).and_then(|_| stream.flush()).map_err(|e| e.to_string())?;
+
+    let status = read_status(&mut stream)?;
+    match status.split_whitespace().nth(1) {
+      Some("100" | "200") => {}
+      Some("401") => return Err(format!("wrong credentials for {} ({status})", destination.path())),
+      Some("403") => return Err(format!("mount point {} is refused or in use ({status})", destination.path())),
+      _ => return Err(format!("unexpected answer from the server: {status}")),
+    }
+    set_read_poll(&stream).map_err(|e| e.to_string())?;
+    Ok(Self { stream })
+  }
+}
+
+/// Reads the response header, and returns its status line.
+pub(super) fn read_status(stream: &mut impl Read) -> Result<String, String> {
+  let mut head = Vec::new();
+  let mut byte = [0u8];
+  while !head.ends_with(b"\r\n\r\n") {
+    if head.len() == MAX_RESPONSE {
+      return Err("the server's response is too long".to_string());
+    }
+    match stream.read(&mut byte) {
+      Ok(0) => return Err("the server closed the connection".to_string()),
+      Ok(_) => head.push(byte[0]),
+      Err(e) => return Err(e.to_string()),
+    }
+  }
+  let head = String::from_utf8_lossy(&head);
+  Ok(head.lines().next().unwrap_or_default().trim().to_string())
+}
+
+impl Sink for IcecastSink {
+  fn send(&mut self, page: &[u8]) -> Result<(), String> {
+    self.stream.write_all(page).map_err(|e| e.to_string())
+  }
+
+  /// Icecast does not answer during the stream, but a closed connection reads as the end of it.
+  fn poll(&mut self) -> Result<(), String> {
+    let mut buf = [0u8; 512];
+    loop {
+      match self.stream.read(&mut buf) {
+        Ok(0) => return Err("the server closed the connection".to_string()),
+        Ok(_) => {}
+        Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => return Ok(()),
+        Err(e) => return Err(e.to_string()),
+      }
+    }
+  }
+
+  fn close(&mut self) {
+    let _ = self.stream.flush();
+    match &mut self.stream {
+      #[cfg(feature = "native-tls")]
+      MaybeTlsStream::NativeTls(s) => { let _ = s.shutdown(); }
+      #[cfg(feature = "rustls")]
+      MaybeTlsStream::Rustls(s) => {
+        s.conn.send_close_notify();
+        let _ = s.flush();
+      }
+      _ => {}
+    }
+  }
+}
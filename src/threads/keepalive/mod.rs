@@ -0,0 +1,137 @@
+use serde::{Deserialize, Serialize};
+use socket2::{SockRef, TcpKeepalive};
+use tungstenite::stream::MaybeTlsStream;
+
+use std::{
+  io,
+  net::{TcpStream, ToSocketAddrs},
+  time::{Duration, Instant},
+};
+
+use crate::config::TauConfigError;
+
+/// How long a read waits for data from the server, once connected.
+const READ_POLL: Duration = Duration::from_millis(1);
+
+/// How a connection is checked to still be alive. Times are in seconds.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct KeepaliveConfig {
+  /// Time between WebSocket pings, and between TCP keepalive probes.
+  pub ping_interval: f64,
+  /// The connection is declared dead when nothing is heard from the server for this long,
+  /// or when a write blocks for this long. Also bounds connecting and the handshake.
+  pub timeout: f64,
+}
+
+impl Default for KeepaliveConfig {
+  fn default() -> Self {
+    Self { ping_interval: 5.0, timeout: 15.0 }
+  }
+}
+
+impl KeepaliveConfig {
+  pub fn is_default(&self) -> bool { *self == Self::default() }
+
+  pub fn validate(&self) -> Result<(), TauConfigError> {
+    if self.ping_interval <= 0.0 {
+      return Err(TauConfigError::InvalidKeepalive(format!("ping_interval must be above 0 s, got {}", self.ping_interval)));
+    }
+    if self.timeout <= self.ping_interval {
+      return Err(TauConfigError::InvalidKeepalive(format!(
+        "timeout must be longer than ping_interval ({} s), got {}", self.ping_interval, self.timeout
+      )));
+    }
+    Ok(())
+  }
+
+  pub fn ping_interval(&self) -> Duration { Duration::from_secs_f64(self.ping_interval) }
+
+  pub fn timeout(&self) -> Duration { Duration::from_secs_f64(self.timeout) }
+
+  /// Opens a TCP connection to `host:port`, with keepalive probes and a write timeout,
+  /// so a half-open link is noticed instead of blocking the stream.
+  pub fn connect(&self, host: &str, port: u16) -> io::Result<TcpStream> {
+    let mut last_err = io::Error::new(io::ErrorKind::NotFound, format!("{host} did not resolve to an address"));
+    for addr in (host, port).to_socket_addrs()? {
+      match TcpStream::connect_timeout(&addr, self.timeout()) {
+        Ok(stream) => {
+          self.configure(&stream)?;
+          return Ok(stream);
+        }
+        Err(e) => last_err = e,
+      }
+    }
+    Err(last_err)
+  }
+
+  fn configure(&self, stream: &TcpStream) -> io::Result<()> {
+    // TCP keepalive is set in whole seconds
+    let secs = self.ping_interval.ceil().max(1.0);
+    let interval = Duration::from_secs_f64(secs);
+    // Enough unanswered probes to cover the timeout
+    let retries = (self.timeout / secs).ceil().max(1.0) as u32;
+    let keepalive = TcpKeepalive::new()
+      .with_time(interval)
+      .with_interval(interval)
+      .with_retries(retries);
+    SockRef::from(stream).set_tcp_keepalive(&keepalive)?;
+    stream.set_nodelay(true)?;
+    stream.set_write_timeout(Some(self.timeout()))?;
+    // Bounds the handshake, lowered to READ_POLL once connected
+    stream.set_read_timeout(Some(self.timeout()))
+  }
+}
+
+/// Shortens the read timeout of an open connection, so that checking for data
+/// from the server does not hold up the stream.
+pub fn set_read_poll(stream: &MaybeTlsStream<TcpStream>) -> io::Result<()> {
+  let tcp = match stream {
+    MaybeTlsStream::Plain(s) => s,
+    #[cfg(feature = "native-tls")]
+    MaybeTlsStream::NativeTls(s) => s.get_ref(),
+    #[cfg(feature = "rustls")]
+    MaybeTlsStream::Rustls(s) => &s.sock,
+    _ => return Err(io::Error::new(io::ErrorKind::Unsupported, "unsupported stream type")),
+  };
+  tcp.set_read_timeout(Some(READ_POLL))
+}
+
+pub fn validate_timeout(secs: f64) -> Result<f64, TauConfigError> {
+  if secs <= 0.0 {
+    return Err(TauConfigError::InvalidKeepalive(format!("times must be above 0 s, got {secs}")));
+  }
+  Ok(secs)
+}
+
+/// Tracks when the server was last heard from, and when to ping it next.
+pub struct Liveness {
+  interval: Duration,
+  timeout: Duration,
+  last_seen: Instant,
+  last_ping: Instant,
+}
+
+impl Liveness {
+  pub fn new(config: &KeepaliveConfig) -> Self {
+    let now = Instant::now();
+    Self { interval: config.ping_interval(), timeout: config.timeout(), last_seen: now, last_ping: now }
+  }
+
+  /// Any frame from the server counts, not only pongs.
+  pub fn seen(&mut self) {
+    self.last_seen = Instant::now();
+  }
+
+  /// Returns true once per interval, when a ping is due.
+  pub fn ping_due(&mut self) -> bool {
+    if self.last_ping.elapsed() < self.interval { return false; }
+    self.last_ping = Instant::now();
+    true
+  }
+
+  /// How long the server has not answered, if that is past the timeout.
+  pub fn dead(&self) -> Option<Duration> {
+    Some(self.last_seen.elapsed()).filter(|silent| *silent > self.timeout)
+  }
+}
@@ -1 +1,10 @@
+pub mod destination;
+pub mod icecast;
+pub mod keepalive;
+pub mod pages;
+pub mod proxy;
+pub mod reconnect;
+pub mod sink;
+pub mod tls;
+pub mod transport;
 pub mod ws;
@@ -0,0 +1,86 @@
+use std::collections::VecDeque;
+
+use crate::util::consts::DEFAULT_SR;
+
+/// `header_type` flag of the first page of a logical stream.
+const BOS: u8 = 0x02;
+/// Granule position of a page on which no packet ends.
+const NO_GRANULE: u64 = u64::MAX;
+
+fn is_bos(page: &[u8]) -> bool {
+  page.get(5).is_some_and(|flags| flags & BOS != 0)
+}
+
+fn granule(page: &[u8]) -> u64 {
+  page.get(6..14).map_or(NO_GRANULE, |g| u64::from_le_bytes(g.try_into().unwrap_or_default()))
+}
+
+/// OpusHead and OpusTags are on the first pages of a stream, with a granule position of 0.
+/// Pages in between that only carry a part of a large OpusTags (e.g. with cover art) have none.
+/// Audio pages always end a packet, as the encoder is flushed every frame.
+fn is_header(page: &[u8]) -> bool {
+  is_bos(page) || matches!(granule(page), 0 | NO_GRANULE)
+}
+
+/// Keeps what a new connection needs to decode the stream: the header pages of
+/// the current (chained) stream, and the audio that could not be sent during an outage.
+pub struct StreamPages {
+  headers: Vec<Vec<u8>>,
+  backlog: VecDeque<Vec<u8>>,
+  /// Longest stretch of audio kept in the backlog, in samples.
+  max_backlog: u64,
+}
+
+impl StreamPages {
+  /// `replay` is how many seconds of audio are kept during an outage, 0 to catch up to live.
+  pub fn new(replay: f64) -> Self {
+    Self {
+      headers: Vec::new(),
+      backlog: VecDeque::new(),
+      max_backlog: (replay * DEFAULT_SR as f64) as u64,
+    }
+  }
+
+  /// Follows every page coming from the encoder, whether it is sent or kept.
+  pub fn observe(&mut self, page: &[u8]) {
+    if is_bos(page) {
+      // A chained stream, with new tags
+      self.headers.clear();
+    }
+    if is_header(page) {
+      self.headers.push(page.to_vec());
+    }
+  }
+
+  /// Keeps a page that could not be sent, to replay it on the next connection.
+  /// Header pages are left out, they are sent anyway.
+  pub fn keep(&mut self, page: Vec<u8>) {
+    if is_header(&page) {
+      if is_bos(&page) {
+        // The older audio can not be decoded with the new stream's headers
+        self.backlog.clear();
+      }
+      return;
+    }
+    if self.max_backlog == 0 { return; }
+    self.backlog.push_back(page);
+    while let (Some(first), Some(last)) = (self.backlog.front(), self.backlog.back())
+      && granule(last).saturating_sub(granule(first)) > self.max_backlog
+    {
+      self.backlog.pop_front();
+    }
+  }
+
+  /// Seconds of audio waiting to be replayed.
+  pub fn backlog_secs(&self) -> f64 {
+    match (self.backlog.front(), self.backlog.back()) {
+      (Some(first), Some(last)) => granule(last).saturating_sub(granule(first)) as f64 / DEFAULT_SR as f64,
+      _ => 0.0,
+    }
+  }
+
+  /// Pages to send first on a new connection: the headers, followed by the backlog.
+  pub fn resume(&mut self) -> VecDeque<Vec<u8>> {
+    self.headers.iter().cloned().chain(self.backlog.drain(..)).collect()
+  }
+}
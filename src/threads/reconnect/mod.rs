@@ -0,0 +1,176 @@
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use std::{
+  sync::atomic::{AtomicBool, Ordering},
+  time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::config::TauConfigError;
+
+/// Granularity of the wait between attempts, so a shutdown is not held up by a long delay.
+const WAIT_STEP: Duration = Duration::from_millis(50);
+
+/// What happens to the audio encoded while the server can not be reached.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum OutagePolicy {
+  /// Sends the missed audio once reconnected, up to `max_replay` seconds of it.
+  /// The stream stays that much behind live for the rest of the session.
+  #[default]
+  Replay,
+  /// Drops the missed audio, the stream continues live
+  Drop,
+}
+
+/// How to retry the connection to the server, when it can not be reached or the connection is lost.
+/// Delays are in seconds.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct ReconnectConfig {
+  /// Wait after the first failed attempt.
+  pub initial_delay: f64,
+  /// Each following wait is this many times longer than the previous one.
+  pub multiplier: f64,
+  /// Upper bound of the wait, jitter included.
+  pub max_delay: f64,
+  /// Fraction by which each wait is randomly shortened or lengthened, 0 to 1,
+  /// so several clients do not retry in lockstep.
+  pub jitter: f64,
+  /// Ends the session after this many failed attempts in a row.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub max_attempts: Option<u32>,
+  /// Ends the session when the server could not be reached for this long.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub give_up_after: Option<f64>,
+  pub on_outage: OutagePolicy,
+  /// Most audio kept for replay, the oldest is dropped beyond this.
+  pub max_replay: f64,
+}
+
+impl Default for ReconnectConfig {
+  fn default() -> Self {
+    Self {
+      initial_delay: 0.5,
+      multiplier: 2.0,
+      max_delay: 30.0,
+      jitter: 0.2,
+      max_attempts: None,
+      give_up_after: None,
+      on_outage: OutagePolicy::default(),
+      max_replay: 10.0,
+    }
+  }
+}
+
+impl ReconnectConfig {
+  pub fn is_default(&self) -> bool { *self == Self::default() }
+
+  pub fn validate(&self) -> Result<(), TauConfigError> {
+    if self.initial_delay <= 0.0 {
+      return Err(TauConfigError::InvalidReconnect(format!("initial_delay must be above 0 s, got {}", self.initial_delay)));
+    }
+    validate_multiplier(self.multiplier)?;
+    if self.max_delay < self.initial_delay {
+      return Err(TauConfigError::InvalidReconnect(format!(
+        "max_delay must be at least initial_delay ({} s), got {}", self.initial_delay, self.max_delay
+      )));
+    }
+    validate_jitter(self.jitter)?;
+    if self.max_attempts == Some(0) {
+      return Err(TauConfigError::InvalidReconnect("max_attempts must be at least 1".to_string()));
+    }
+    if let Some(s) = self.give_up_after && s <= 0.0 {
+      return Err(TauConfigError::InvalidReconnect(format!("give_up_after must be above 0 s, got {s}")));
+    }
+    if self.max_replay < 0.0 {
+      return Err(TauConfigError::InvalidReconnect(format!("max_replay must be 0 s or more, got {}", self.max_replay)));
+    }
+    Ok(())
+  }
+
+  /// Seconds of audio kept while disconnected.
+  pub fn replay_secs(&self) -> f64 {
+    match self.on_outage {
+      OutagePolicy::Replay => self.max_replay,
+      OutagePolicy::Drop => 0.0,
+    }
+  }
+}
+
+pub fn validate_multiplier(multiplier: f64) -> Result<f64, TauConfigError> {
+  if multiplier < 1.0 {
+    return Err(TauConfigError::InvalidReconnect(format!("multiplier must be at least 1, got {multiplier}")));
+  }
+  Ok(multiplier)
+}
+
+pub fn validate_jitter(jitter: f64) -> Result<f64, TauConfigError> {
+  if !(0.0..=1.0).contains(&jitter) {
+    return Err(TauConfigError::InvalidReconnect(format!("jitter must be 0 to 1, got {jitter}")));
+  }
+  Ok(jitter)
+}
+
+/// Exponential backoff over one outage, from the first failed attempt until the next successful connection.
+pub struct Backoff {
+  config: ReconnectConfig,
+  attempts: u32,
+  since: Option<Instant>,
+  rng: u32,
+}
+
+impl Backoff {
+  pub fn new(config: ReconnectConfig) -> Self {
+    let seed = SystemTime::now().duration_since(UNIX_EPOCH).map_or(1, |d| d.subsec_nanos());
+    Self { config, attempts: 0, since: None, rng: seed | 1 }
+  }
+
+  /// Failed attempts since the last successful connection.
+  pub fn attempts(&self) -> u32 { self.attempts }
+
+  /// Records a failed attempt. Returns how long to wait before the next one,
+  /// or `None` if the policy says to give up.
+  pub fn failed(&mut self) -> Option<Duration> {
+    self.attempts += 1;
+    let since = *self.since.get_or_insert_with(Instant::now);
+    if self.config.max_attempts.is_some_and(|max| self.attempts >= max) {
+      return None;
+    }
+
+    let spread = self.uniform();
+    let c = &self.config;
+    let base = c.initial_delay * c.multiplier.powi(self.attempts as i32 - 1);
+    let mut delay = Duration::from_secs_f64((base.min(c.max_delay) * (1.0 + c.jitter * spread)).min(c.max_delay));
+    if let Some(limit) = c.give_up_after {
+      let left = Duration::from_secs_f64(limit).checked_sub(since.elapsed())?;
+      delay = delay.min(left);
+    }
+    Some(delay)
+  }
+
+  /// Starts over, after a successful connection.
+  pub fn reset(&mut self) {
+    self.attempts = 0;
+    self.since = None;
+  }
+
+  /// Uniformly distributed in -1 to 1, from a xorshift generator.
+  fn uniform(&mut self) -> f64 {
+    self.rng ^= self.rng << 13;
+    self.rng ^= self.rng >> 17;
+    self.rng ^= self.rng << 5;
+    self.rng as f64 / u32::MAX as f64 * 2.0 - 1.0
+  }
+}
+
+/// Waits for `delay`, or until `shutdown` is set. `idle` is called in a loop meanwhile,
+/// and may block for at most the duration it is passed, e.g. to keep draining a channel.
+pub fn wait(delay: Duration, shutdown: &AtomicBool, mut idle: impl FnMut(Duration)) {
+  let until = Instant::now() + delay;
+  while !shutdown.load(Ordering::SeqCst) {
+    let left = until.saturating_duration_since(Instant::now());
+    if left.is_zero() { break; }
+    idle(left.min(WAIT_STEP));
+  }
+}
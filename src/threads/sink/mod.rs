@@ -0,0 +1,233 @@
+use std::{
+  collections::VecDeque,
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc
+  },
+  time::{Duration, Instant},
+};
+
+use crossbeam::channel::{Receiver, RecvTimeoutError, Sender, TrySendError};
+
+use super::{
+  pages::StreamPages,
+  reconnect::{Backoff, ReconnectConfig, wait},
+};
+
+/// How long a connection waits for the next page before checking on the server.
+const RECV_TIMEOUT: Duration = Duration::from_millis(100);
+/// How long a connection has to last for the backoff to start over. One the server
+/// drops sooner counts as a failed attempt, so it is not retried in a tight loop.
+const STABLE_AFTER: Duration = Duration::from_secs(10);
+/// Pages queued for each destination, over a minute of audio. Beyond this,
+/// pages are dropped for a destination that can not keep up.
+pub const QUEUE_LEN: usize = 4096;
+
+/// A connection to a server, that the Ogg pages of the stream are sent over.
+pub trait Sink {
+  fn send(&mut self, page: &[u8]) -> Result<(), String>;
+  /// Called between pages, at least every 100 ms. Fails when the connection is found dead.
+  fn poll(&mut self) -> Result<(), String> { Ok(()) }
+  /// Ends the stream cleanly, at the end of the session.
+  fn close(&mut self) {}
+}
+
+/// What was sent to a server over the session, for the summary at the end.
+#[derive(Debug, Default)]
+pub struct StreamStats {
+  pub address: String,
+  /// Bytes of Ogg pages sent.
+  pub sent: u64,
+  /// Times the connection was lost.
+  pub outages: u32,
+  /// Whether the reconnect policy gave up on the server.
+  pub gave_up: bool,
+}
+
+/// Hands every page from the encoder to the queue of each destination, without
+/// waiting on any of them, until the encoder ends the stream. Ends the session
+/// once every destination has given up.
+pub fn fan_out(shutdown: &AtomicBool, opus_rx: &Receiver<Vec<u8>>, queues: Vec<(String, Sender<Vec<u8>>)>) {
+  let mut queues: Vec<_> = queues.into_iter().map(|(address, tx)| (address, tx, false)).collect();
+  for page in opus_rx.iter() {
+    queues.retain_mut(|(address, tx, behind)| match tx.try_send(page.clone()) {
+      Ok(()) => {
+        *behind = false;
+        true
+      }
+      Err(TrySendError::Full(_)) => {
+        if !*behind { crate::ui::print_falling_behind(address); }
+        *behind = true;
+        true
+      }
+      Err(TrySendError::Disconnected(_)) => false,
+    });
+    // Ends the session, so the recording is completed
+    if queues.is_empty() && !shutdown.swap(true, Ordering::SeqCst) {
+      crate::ui::print_no_destinations();
+    }
+  }
+}
+
+/// Why an attempt to stream to the server failed.
+enum Failure {
+  /// The connection could not be made.
+  Connect(String),
+  /// The server dropped the connection right after accepting it.
+  Dropped(String),
+}
+
+/// Streams the pages from `opus_rx` over the connections made by `connect`,
+/// reconnecting according to `reconnect` until the encoder ends the stream,
+/// or the policy gives up on the server.
+pub fn stream_loop<S: Sink>(
+  shutdown: Arc<AtomicBool>,
+  opus_rx: &Receiver<Vec<u8>>,
+  address: &str,
+  reconnect: ReconnectConfig,
+  mut connect: impl FnMut() -> Result<S, String>,
+) -> StreamStats {
+  let mut stats = StreamStats { address: address.to_string(), ..Default::default() };
+  let mut pages = StreamPages::new(reconnect.replay_secs());
+  let mut backoff = Backoff::new(reconnect);
+  // Keeps draining the encoder while there is no connection, so it never blocks
+  let buffer = |pages: &mut StreamPages, timeout: Duration| {
+    if let Ok(page) = opus_rx.recv_timeout(timeout) {
+      pages.observe(&page);
+      pages.keep(page);
+    }
+  };
+
+  loop {
+    if shutdown.load(Ordering::SeqCst) { break; }
+    let failure = match connect() {
+      Ok(mut sink) => {
+        if backoff.attempts() > 0 {
+          crate::ui::print_reconnected(address, backoff.attempts(), pages.backlog_secs());
+        }
+        let connected = Instant::now();
+        let Err(reason) = handle_connection(&mut sink, opus_rx, &mut pages, &mut stats.sent) else { break };
+        // What is left after a shutdown can not wait for another connection
+        if shutdown.load(Ordering::SeqCst) { break; }
+        stats.outages += 1;
+        if connected.elapsed() >= STABLE_AFTER {
+          crate::ui::print_connection_lost(address, &reason);
+          backoff.reset();
+          continue;
+        }
+        Failure::Dropped(reason)
+      }
+      Err(e) => Failure::Connect(e),
+    };
+    match backoff.failed() {
+      Some(delay) => {
+        match failure {
+          Failure::Connect(e) => crate::ui::print_connect_failed(address, backoff.attempts(), &e, delay),
+          Failure::Dropped(reason) => crate::ui::print_dropped(address, backoff.attempts(), &reason, delay),
+        }
+        wait(delay, &shutdown, |timeout| buffer(&mut pages, timeout));
+      }
+      None => {
+        crate::ui::print_giving_up(address, backoff.attempts());
+        stats.gave_up = true;
+        break;
+      }
+    }
+  }
+  stats
+}
+
+/// Sends the headers and the backlog, then every page from the encoder, until the
+/// connection fails. Pages that could not be sent are kept in `pages`. After a
+/// shutdown, the encoder's last pages are sent and the sink is closed, which ends
+/// it with `Ok`. Otherwise returns why the connection was lost.
+fn handle_connection(
+  sink: &mut impl Sink,
+  rx: &Receiver<Vec<u8>>,
+  pages: &mut StreamPages,
+  sent: &mut u64,
+) -> Result<(), String> {
+  let mut pending: VecDeque<Vec<u8>> = pages.resume();
+  let mut last_poll = Instant::now();
+  let lost = |pages: &mut StreamPages, pending: VecDeque<Vec<u8>>, reason: String| {
+    pending.into_iter().for_each(|p| pages.keep(p));
+    Err(reason)
+  };
+  loop {
+    if last_poll.elapsed() >= RECV_TIMEOUT {
+      last_poll = Instant::now();
+      if let Err(e) = sink.poll() {
+        return lost(pages, pending, e);
+      }
+    }
+
+    let page = match pending.pop_front() {
+      Some(page) => page,
+      None => match rx.recv_timeout(RECV_TIMEOUT) {
+        Ok(page) => {
+          pages.observe(&page);
+          page
+        }
+        Err(RecvTimeoutError::Timeout) => continue,
+        // The encoder has ended the stream
+        Err(RecvTimeoutError::Disconnected) => {
+          sink.close();
+          return Ok(());
+        }
+      },
+    };
+    if let Err(e) = sink.send(&page) {
+      pages.keep(page);
+      return lost(pages, pending, e);
+    }
+    *sent += page.len() as u64;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::cell::Cell;
+
+  /// Accepted by the server, then dropped as soon as a page is sent.
+  struct Refused;
+
+  impl Sink for Refused {
+    fn send(&mut self, _page: &[u8]) -> Result<(), String> { Err("mountpoint in use".to_string()) }
+  }
+
+  /// An audio page, with a granule position past the headers.
+  fn page(granule: u64) -> Vec<u8> {
+    let mut page = b"OggS\0\0".to_vec();
+    page.extend(granule.to_le_bytes());
+    page.resize(64, 0);
+    page
+  }
+
+  #[test]
+  fn backs_off_from_connections_dropped_right_away() {
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let (tx, rx) = crossbeam::channel::unbounded();
+    (1..=10).for_each(|i| tx.send(page(i * 960)).unwrap());
+    let reconnect = ReconnectConfig {
+      initial_delay: 0.01,
+      max_delay: 0.05,
+      max_attempts: Some(3),
+      ..Default::default()
+    };
+    let connects = Cell::new(0);
+    let started = Instant::now();
+    let stats = stream_loop(shutdown.clone(), &rx, "radio.example.com:443", reconnect, || {
+      connects.set(connects.get() + 1);
+      // Without backing off, this would go on until the session ends
+      if connects.get() == 100 { shutdown.store(true, Ordering::SeqCst); }
+      Ok(Refused)
+    });
+    assert_eq!(connects.get(), 3);
+    assert!(stats.gave_up);
+    assert_eq!(stats.outages, 3);
+    // Waited after the first and the second drop
+    assert!(started.elapsed() >= Duration::from_millis(2 * 8), "{:?}", started.elapsed());
+  }
+}
@@ -0,0 +1,306 @@
+use serde::{Deserialize, Serialize};
+use tungstenite::stream::MaybeTlsStream;
+
+use std::{net::TcpStream, path::PathBuf};
+
+/// How the server's certificate is checked, and the client certificate to present.
+/// Only used with `tls = true`.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct TlsOptions {
+  /// PEM file with the certificates of the CAs to trust, instead of the default roots:
+  /// the system's with native-tls, the Mozilla ones bundled with rustls.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub ca_file: Option<PathBuf>,
+  /// SHA-256 fingerprint of the server's certificate, in hex. A certificate that
+  /// matches is trusted without a CA, e.g. a self-signed one.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub pin_sha256: Option<String>,
+  /// Accepts any certificate. Only for testing against a development server.
+  #[serde(skip_serializing_if = "std::ops::Not::not", deserialize_with = "flattened_bool")]
+  pub insecure_skip_verify: bool,
+  /// PEM certificate (chain) presented to servers that require mutual TLS.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub client_cert: Option<PathBuf>,
+  /// PEM PKCS#8 private key of `client_cert`.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub client_key: Option<PathBuf>,
+}
+
+/// The options are flattened into the server's settings, which hands their values over as read.
+/// A `TAU_INSECURE_SKIP_VERIFY=true` environment variable then comes as a string.
+fn flattened_bool<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+  #[derive(Deserialize)]
+  #[serde(untagged)]
+  enum Flag {
+    Bool(bool),
+    Str(String),
+  }
+  match Flag::deserialize(deserializer)? {
+    Flag::Bool(b) => Ok(b),
+    Flag::Str(s) => s.trim().parse()
+      .map_err(|_| serde::de::Error::custom(format!("expected true or false, got '{s}'"))),
+  }
+}
+
+impl TlsOptions {
+  pub fn validate(&self) -> Result<(), String> {
+    if self.client_cert.is_some() != self.client_key.is_some() {
+      return Err("client_cert and client_key go together".to_string());
+    }
+    if self.pin_sha256.is_some() && self.insecure_skip_verify {
+      return Err("set either pin_sha256 or insecure_skip_verify".to_string());
+    }
+    // Either one replaces the check against the CA, which would leave ca_file unused
+    if self.ca_file.is_some() && (self.pin_sha256.is_some() || self.insecure_skip_verify) {
+      return Err("ca_file goes with neither pin_sha256 nor insecure_skip_verify".to_string());
+    }
+    if let Some(pin) = &self.pin_sha256 {
+      parse_pin(pin)?;
+    }
+    Ok(())
+  }
+
+  /// Overrides the options that are set in `other`. A pin and skipping verification
+  /// replace each other.
+  pub fn merge(&mut self, other: &TlsOptions) {
+    if other.ca_file.is_some()    {self.ca_file     = other.ca_file.clone()}
+    if other.pin_sha256.is_some() {
+      self.pin_sha256 = other.pin_sha256.clone();
+      self.insecure_skip_verify = false;
+    }
+    if other.insecure_skip_verify {
+      self.insecure_skip_verify = true;
+      self.pin_sha256 = None;
+    }
+    if other.client_cert.is_some() {self.client_cert = other.client_cert.clone()}
+    if other.client_key.is_some() {self.client_key  = other.client_key.clone()}
+  }
+}
+
+/// Reads a fingerprint as printed by `openssl x509 -noout -fingerprint -sha256`, colons optional.
+fn parse_pin(pin: &str) -> Result<[u8; 32], String> {
+  let hex: String = pin.chars().filter(|c| *c != ':').collect();
+  let invalid = || format!("pin_sha256 must be 64 hex digits, got '{pin}'");
+  if hex.len() != 64 || !hex.chars().all(|c| c.is_ascii_hexdigit()) { return Err(invalid()); }
+  let mut bytes = [0u8; 32];
+  for (i, byte) in bytes.iter_mut().enumerate() {
+    *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).map_err(|_| invalid())?;
+  }
+  Ok(bytes)
+}
+
+#[cfg(all(feature = "native-tls", not(feature = "rustls")))]
+fn read(path: &PathBuf) -> Result<Vec<u8>, String> {
+  std::fs::read(path).map_err(|e| format!("could not read {}: {e}", path.display()))
+}
+
+/// Sets up TLS connections to a destination, prepared once per session.
+/// Uses native-tls, or rustls when built with the `rustls` feature.
+pub struct TlsConnector {
+  #[cfg(all(feature = "native-tls", not(feature = "rustls")))]
+  connector: native_tls::TlsConnector,
+  #[cfg(feature = "rustls")]
+  config: std::sync::Arc<rustls::ClientConfig>,
+  #[cfg_attr(not(all(feature = "native-tls", not(feature = "rustls"))), allow(dead_code))]
+  pin: Option<[u8; 32]>,
+}
+
+impl TlsConnector {
+  #[cfg(all(feature = "native-tls", not(feature = "rustls")))]
+  pub fn new(options: &TlsOptions) -> Result<Self, String> {
+    use native_tls::{Certificate, Identity};
+
+    let pin = options.pin_sha256.as_deref().map(parse_pin).transpose()?;
+    let mut builder = native_tls::TlsConnector::builder();
+    if let Some(ca) = &options.ca_file {
+      let certs = Certificate::stack_from_pem(&read(ca)?).map_err(|e| format!("{}: {e}", ca.display()))?;
+      for cert in certs {
+        builder.add_root_certificate(cert);
+      }
+      builder.disable_built_in_roots(true);
+    }
+    // A pinned certificate is checked after the handshake instead
+    if pin.is_some() || options.insecure_skip_verify {
+      builder.danger_accept_invalid_certs(true).danger_accept_invalid_hostnames(true);
+    }
+    if let (Some(cert), Some(key)) = (&options.client_cert, &options.client_key) {
+      let identity = Identity::from_pkcs8(&read(cert)?, &read(key)?)
+        .map_err(|e| format!("client certificate {}: {e}", cert.display()))?;
+      builder.identity(identity);
+    }
+    let connector = builder.build().map_err(|e| e.to_string())?;
+    Ok(Self { connector, pin })
+  }
+
+  #[cfg(all(feature = "native-tls", not(feature = "rustls")))]
+  pub fn connect(&self, host: &str, tcp: TcpStream) -> Result<MaybeTlsStream<TcpStream>, String> {
+    let stream = self.connector.connect(host, tcp).map_err(|e| e.to_string())?;
+    if let Some(pin) = &self.pin {
+      let cert = stream.peer_certificate()
+        .map_err(|e| e.to_string())?
+        .ok_or("the server sent no certificate")?;
+      check_pin(&cert.to_der().map_err(|e| e.to_string())?, pin)?;
+    }
+    Ok(MaybeTlsStream::NativeTls(stream))
+  }
+
+  #[cfg(feature = "rustls")]
+  pub fn new(options: &TlsOptions) -> Result<Self, String> {
+    use rustls::{
+      ClientConfig, RootCertStore,
+      pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject},
+    };
+    use std::sync::Arc;
+
+    let pin = options.pin_sha256.as_deref().map(parse_pin).transpose()?;
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = ClientConfig::builder_with_provider(provider.clone())
+      .with_safe_default_protocol_versions()
+      .map_err(|e| e.to_string())?;
+    let builder = if pin.is_some() || options.insecure_skip_verify {
+      builder.dangerous().with_custom_certificate_verifier(Arc::new(pinned::PinnedVerifier::new(pin, provider)))
+    } else {
+      let mut roots = RootCertStore::empty();
+      match &options.ca_file {
+        Some(ca) => {
+          let certs = CertificateDer::pem_file_iter(ca).map_err(|e| format!("{}: {e}", ca.display()))?;
+          for cert in certs {
+            roots.add(cert.map_err(|e| format!("{}: {e}", ca.display()))?).map_err(|e| e.to_string())?;
+          }
+        }
+        None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+      }
+      builder.with_root_certificates(roots)
+    };
+    let config = match (&options.client_cert, &options.client_key) {
+      (Some(cert), Some(key)) => {
+        let certs = CertificateDer::pem_file_iter(cert)
+          .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+          .map_err(|e| format!("{}: {e}", cert.display()))?;
+        let key = PrivateKeyDer::from_pem_file(key).map_err(|e| format!("{}: {e}", key.display()))?;
+        builder.with_client_auth_cert(certs, key).map_err(|e| e.to_string())?
+      }
+      _ => builder.with_no_client_auth(),
+    };
+    Ok(Self { config: Arc::new(config), pin })
+  }
+
+  #[cfg(feature = "rustls")]
+  pub fn connect(&self, host: &str, tcp: TcpStream) -> Result<MaybeTlsStream<TcpStream>, String> {
+    use rustls::{ClientConnection, StreamOwned, pki_types::ServerName};
+
+    let name = ServerName::try_from(host.to_string()).map_err(|e| e.to_string())?;
+    let conn = ClientConnection::new(self.config.clone(), name).map_err(|e| e.to_string())?;
+    let mut stream = StreamOwned::new(conn, tcp);
+    // Handshakes right away, so a refused certificate fails the connection attempt
+    while stream.conn.is_handshaking() {
+      stream.conn.complete_io(&mut stream.sock).map_err(|e| e.to_string())?;
+    }
+    Ok(MaybeTlsStream::Rustls(stream))
+  }
+
+  #[cfg(not(any(feature = "native-tls", feature = "rustls")))]
+  pub fn new(_options: &TlsOptions) -> Result<Self, String> {
+    Err(NO_BACKEND.to_string())
+  }
+
+  #[cfg(not(any(feature = "native-tls", feature = "rustls")))]
+  pub fn connect(&self, _host: &str, _tcp: TcpStream) -> Result<MaybeTlsStream<TcpStream>, String> {
+    Err(NO_BACKEND.to_string())
+  }
+}
+
+#[cfg(not(any(feature = "native-tls", feature = "rustls")))]
+const NO_BACKEND: &str = "built without TLS, enable the native-tls or rustls feature";
+
+#[cfg(any(feature = "native-tls", feature = "rustls"))]
+fn check_pin(der: &[u8], pin: &[u8; 32]) -> Result<(), String> {
+  use sha2::{Digest, Sha256};
+
+  let digest = Sha256::digest(der);
+  if digest.as_slice() != pin {
+    let hex: Vec<String> = digest.iter().map(|b| format!("{b:02X}")).collect();
+    return Err(format!("the server's certificate does not match pin_sha256, it is {}", hex.join(":")));
+  }
+  Ok(())
+}
+
+#[cfg(feature = "rustls")]
+mod pinned {
+  use rustls::{
+    DigitallySignedStruct, Error, SignatureScheme,
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{CryptoProvider, verify_tls12_signature, verify_tls13_signature},
+    pki_types::{CertificateDer, ServerName, UnixTime},
+  };
+  use std::sync::Arc;
+
+  /// Accepts the server's certificate if it matches the pin, or any certificate without one.
+  /// The handshake signatures are still checked against the certificate.
+  #[derive(Debug)]
+  pub struct PinnedVerifier {
+    pin: Option<[u8; 32]>,
+    provider: Arc<CryptoProvider>,
+  }
+
+  impl PinnedVerifier {
+    pub fn new(pin: Option<[u8; 32]>, provider: Arc<CryptoProvider>) -> Self {
+      Self { pin, provider }
+    }
+  }
+
+  impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+      &self,
+      end_entity: &CertificateDer<'_>,
+      _intermediates: &[CertificateDer<'_>],
+      _server_name: &ServerName<'_>,
+      _ocsp_response: &[u8],
+      _now: UnixTime,
+    ) -> Result<ServerCertVerified, Error> {
+      if let Some(pin) = &self.pin {
+        super::check_pin(end_entity, pin).map_err(Error::General)?;
+      }
+      Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+      &self,
+      message: &[u8],
+      cert: &CertificateDer<'_>,
+      dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+      verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+      &self,
+      message: &[u8],
+      cert: &CertificateDer<'_>,
+      dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+      verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+      self.provider.signature_verification_algorithms.supported_schemes()
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const PIN: &str = "D8:71:32:1E:78:54:7F:6E:59:66:09:80:E7:11:80:7F:80:E8:FF:2B:AA:75:C1:7F:72:C9:A1:D8:1B:1C:F8:E2";
+
+  #[test]
+  fn refuses_ca_file_with_a_weaker_check() {
+    let ca = TlsOptions { ca_file: Some(PathBuf::from("/etc/tau/studio-ca.pem")), ..Default::default() };
+    assert!(ca.validate().is_ok());
+    assert!(TlsOptions { pin_sha256: Some(PIN.to_string()), ..Default::default() }.validate().is_ok());
+    assert!(TlsOptions { pin_sha256: Some(PIN.to_string()), ..ca.clone() }.validate().is_err());
+    assert!(TlsOptions { insecure_skip_verify: true, ..ca }.validate().is_err());
+  }
+}
@@ -0,0 +1,52 @@
+use tungstenite::stream::MaybeTlsStream;
+
+use std::net::TcpStream;
+
+use super::{
+  destination::Destination,
+  keepalive::KeepaliveConfig,
+  proxy::{DIRECT, Proxy},
+  tls::TlsConnector,
+};
+
+/// How a destination is reached: directly or through a proxy, with or without TLS.
+/// Prepared once per session, so that a bad certificate file or proxy URL fails
+/// the session at the start rather than on every connection attempt.
+pub struct Transport {
+  proxy: Option<Proxy>,
+  tls: Option<TlsConnector>,
+}
+
+impl Transport {
+  /// Uses the destination's `proxy`, or the one from the environment if it has none.
+  pub fn new(destination: &Destination) -> Result<Self, String> {
+    let proxy = match destination.proxy.as_deref() {
+      Some(DIRECT) => None,
+      Some(url) => Some(Proxy::parse(url)?),
+      None => Proxy::from_env(&destination.url, destination.port, destination.tls)?,
+    };
+    let tls = destination.tls.then(|| TlsConnector::new(&destination.tls_options)).transpose()?;
+    Ok(Self { proxy, tls })
+  }
+
+  /// The proxy connections go through, if any.
+  pub fn proxy(&self) -> Option<&Proxy> {
+    self.proxy.as_ref()
+  }
+
+  /// Connects to the destination with the keepalive settings, and completes the TLS handshake.
+  pub fn connect(
+    &self,
+    destination: &Destination,
+    keepalive: &KeepaliveConfig,
+  ) -> Result<MaybeTlsStream<TcpStream>, String> {
+    let tcp = match &self.proxy {
+      Some(proxy) => proxy.connect(keepalive, &destination.url, destination.port)?,
+      None => keepalive.connect(&destination.url, destination.port).map_err(|e| e.to_string())?,
+    };
+    match &self.tls {
+      Some(tls) => tls.connect(&destination.url, tcp),
+      None => Ok(MaybeTlsStream::Plain(tcp)),
+    }
+  }
+}
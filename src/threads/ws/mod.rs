@@ -1,16 +1,18 @@
 use std::{
+    io::ErrorKind,
     net::TcpStream, 
     path::Path,
     sync::{
-      atomic::{AtomicBool, Ordering},
+      atomic::AtomicBool,
       Arc
     },
-    thread::{sleep, spawn},
+    thread::spawn,
     time::{Duration, Instant}
 };
 
 use tungstenite::{
-  connect,
+  client,
+  protocol::{CloseFrame, frame::coding::CloseCode},
   http::Uri,
   stream::MaybeTlsStream,
   ClientRequestBuilder,
@@ -21,24 +23,39 @@ use tungstenite::{
 use crossbeam::channel::{Receiver, bounded};
 use ringbuf::traits::Consumer;
 
-use crate::{Credentials, DEFAULT_CH};
+use crate::{metadata::StreamMetadata, util::consts::FRAME_LEN};
+use super::{
+  destination::{Destination, StreamType},
+  icecast::IcecastSink,
+  keepalive::{KeepaliveConfig, Liveness, set_read_poll},
+  reconnect::ReconnectConfig,
+  sink::{QUEUE_LEN, Sink, StreamStats, fan_out, stream_loop},
+  transport::Transport,
+};
+/// How long the server gets to answer the Close frame at the end of the session.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(2);
+
 use crate::audio::{
   audio_capture_loop,
+  encoder::EncoderConfig,
+  recorder::RecordingSettings,
   encode_audio,
   record_audio
 };
 
-const LOG_TIME: Duration = Duration::from_secs(10);
-
+#[allow(clippy::too_many_arguments)]
 pub fn thread(
     mut rx: impl Consumer<Item = f32> + Send + 'static,
-    url: (&str, u16),
-    tls_enabled: bool,
-    filename: Arc<String>,
-    credentials: Credentials,
+    destinations: Vec<Destination>,
+    channels: usize,
+    stream_encoder: EncoderConfig,
+    metadata: Arc<StreamMetadata>,
+    reconnect: ReconnectConfig,
+    keepalive: KeepaliveConfig,
     shutdown: Arc<AtomicBool>
-) -> Result<(), Box<dyn std::error::Error + Send>> {
-  let framesize = 960 * DEFAULT_CH;
+) -> Result<Vec<StreamStats>, Box<dyn std::error::Error + Send>> {
+  let framesize = FRAME_LEN * channels;
+  let destinations = requests(destinations, &metadata, channels, &stream_encoder)?;
   let (opus_tx, opus_rx) = bounded::<Vec<u8>>(4096 * 32);
   let (audio_tx, audio_rx) = bounded::<f32>(4096 * 32);
 
@@ -47,15 +64,12 @@ pub fn thread(
     audio_capture_loop(shutdown_clone, &mut rx, &[audio_tx]);
   });
 
-  let shutdown_clone = shutdown.clone();
   // Encoding thread
   let encoder_thread = spawn(move || {
-    encode_audio(shutdown_clone, filename, &audio_rx, &opus_tx, framesize);
+    encode_audio(metadata, &audio_rx, &opus_tx, channels, framesize, &stream_encoder);
   });
 
-  websocket_connect_loop(shutdown, &opus_rx, &url, &credentials, tls_enabled).map_err(|e| 
-    box_err(&format!("Unexpected websocket error {e}"))
-  )?;
+  let stats = broadcast(shutdown, &opus_rx, destinations, reconnect, &keepalive);
   
   audio_capture_thread.join().map_err(|e|
     box_err(&format!("Audio capture join thread error: {e:?}"))
@@ -64,19 +78,25 @@ pub fn thread(
   encoder_thread.join().map_err(|e|
     box_err(&format!("Encoder thread join error: {e:?}"))
   )?;
-  Ok(())
+  Ok(stats)
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn rec_thread(
     mut rx: impl Consumer<Item = f32> + Send + 'static,
-    url: (&str, u16),
-    tls_enabled: bool,
+    destinations: Vec<Destination>,
+    channels: usize,
+    stream_encoder: EncoderConfig,
+    recording: RecordingSettings,
     path: &Path,
     filename: Arc<String>,
-    credentials: Credentials,
+    metadata: Arc<StreamMetadata>,
+    reconnect: ReconnectConfig,
+    keepalive: KeepaliveConfig,
     shutdown: Arc<AtomicBool>
-) -> Result<(), Box<dyn std::error::Error + Send>> {
-  let framesize = 960 * DEFAULT_CH;
+) -> Result<Vec<StreamStats>, Box<dyn std::error::Error + Send>> {
+  let framesize = FRAME_LEN * channels;
+  let destinations = requests(destinations, &metadata, channels, &stream_encoder)?;
   let (opus_tx, opus_rx) = bounded::<Vec<u8>>(4096 * 32);
   let (encode_tx, encode_rx) = bounded::<f32>(4096 * 32);
   let (record_tx, record_rx) = bounded::<f32>(4096 * 32);
@@ -86,24 +106,19 @@ pub fn rec_thread(
     audio_capture_loop(shutdown_clone, &mut rx, &[encode_tx, record_tx]);
   });
 
-  let shutdown_clone = shutdown.clone();
-  let filename_clone = filename.clone();
+  let metadata_clone = metadata.clone();
   // Encoding thread
   let encoder_thread = spawn(move || {
-    encode_audio(shutdown_clone, filename_clone, &encode_rx, &opus_tx, framesize);
+    encode_audio(metadata_clone, &encode_rx, &opus_tx, channels, framesize, &stream_encoder);
   });
 
-  let filename_clone = filename.clone();
-  let shutdown_clone = shutdown.clone();
-  let out_path = path.join(filename.clone().to_string());
+  let out_path = path.join(filename.to_string());
   // Recording thread
   let recorder_thread = spawn(move || {
-    record_audio(shutdown_clone, filename_clone, &record_rx, &out_path, framesize);
+    record_audio(metadata, &record_rx, &out_path, channels, framesize, &recording);
   });
 
-  websocket_connect_loop(shutdown, &opus_rx, &url, &credentials, tls_enabled).map_err(|e| 
-    box_err(&format!("Unexpected websocket error {e}"))
-  )?;
+  let stats = broadcast(shutdown, &opus_rx, destinations, reconnect, &keepalive);
   
   audio_capture_thread.join().map_err(|e|
     box_err(&format!("Audio capture join thread error: {e:?}"))
@@ -116,74 +131,176 @@ pub fn rec_thread(
   recorder_thread.join().map_err(|e| box_err(
     &format!("Recorder thread join error: {e:?}"))
   )?;
-  Ok(())
+  Ok(stats)
 }
 
-fn handle_websocket(shutdown: Arc<AtomicBool>, ws: &mut WebSocket<MaybeTlsStream<TcpStream>>, rx: &Receiver<Vec<u8>>) {
-  'outer: loop {
-    while let Ok(page) = rx.recv() {
-      if shutdown.load(Ordering::SeqCst) { break 'outer; }
-      if let Err(e) = ws.send(Message::Binary(page.into())) {
-        eprintln!("Websocket send error: {e}");
-        return;
-      }
+/// What is sent to open a connection, built once per session.
+enum Request {
+  WebSocket(ClientRequestBuilder),
+  IceCast(String),
+}
+
+impl Request {
+  fn new(
+    destination: &Destination,
+    metadata: &StreamMetadata,
+    channels: usize,
+    encoder: &EncoderConfig,
+  ) -> Result<Self, String> {
+    match destination.protocol {
+      StreamType::WebSocket => websocket_request(destination).map(Self::WebSocket),
+      StreamType::IceCast => Ok(Self::IceCast(super::icecast::request(destination, metadata, channels, encoder))),
     }
   }
 }
 
+/// A destination, with what is needed to connect to it.
+type Connection = (Destination, Request, Transport);
+
+/// The request and transport of every destination.
+fn requests(
+  destinations: Vec<Destination>,
+  metadata: &StreamMetadata,
+  channels: usize,
+  encoder: &EncoderConfig,
+) -> Result<Vec<Connection>, Box<dyn std::error::Error + Send>> {
+  destinations.into_iter().map(|d| {
+    match (Request::new(&d, metadata, channels, encoder), Transport::new(&d)) {
+      (Ok(request), Ok(transport)) => {
+        if let Some(proxy) = transport.proxy() {
+          crate::ui::print_proxy(&d.address(), &proxy.address());
+        }
+        Ok((d, request, transport))
+      }
+      (Err(e), _) | (_, Err(e)) => Err(box_err(&format!("Invalid destination {}: {e}", d.address()))),
+    }
+  }).collect()
+}
 
-fn websocket_connect_loop(
+/// Streams to every destination from its own thread, each with its own queue of pages
+/// and reconnect state, so that a slow or unreachable server does not hold up the others.
+fn broadcast(
   shutdown: Arc<AtomicBool>,
-  opus_rx: &Receiver<Vec<u8>>, 
-  url: &(&str, u16),
-  credentials: &Credentials,
-  tls_enabled: bool,
-  ) -> Result<(), String> {
-  let connected = Arc::new(AtomicBool::new(false));
-
-  let scheme = if tls_enabled { "wss" } else { "ws" };
-  let uri = match Uri::builder()
+  opus_rx: &Receiver<Vec<u8>>,
+  destinations: Vec<Connection>,
+  reconnect: ReconnectConfig,
+  keepalive: &KeepaliveConfig,
+) -> Vec<StreamStats> {
+  let mut queues = Vec::new();
+  let mut threads = Vec::new();
+  for (destination, request, transport) in destinations {
+    let (tx, rx) = bounded::<Vec<u8>>(QUEUE_LEN);
+    queues.push((destination.address(), tx));
+    let (shutdown, reconnect, keepalive) = (shutdown.clone(), reconnect.clone(), keepalive.clone());
+    threads.push(spawn(move || {
+      stream(shutdown, &rx, &destination, request, &transport, reconnect, &keepalive)
+    }));
+  }
+  fan_out(&shutdown, opus_rx, queues);
+  threads.into_iter().filter_map(|t| t.join().ok()).collect()
+}
+
+/// Streams to `destination` until the session ends, reconnecting when the connection is lost.
+fn stream(
+  shutdown: Arc<AtomicBool>,
+  opus_rx: &Receiver<Vec<u8>>,
+  destination: &Destination,
+  request: Request,
+  transport: &Transport,
+  reconnect: ReconnectConfig,
+  keepalive: &KeepaliveConfig,
+) -> StreamStats {
+  let address = destination.address();
+  match request {
+    Request::WebSocket(request) => stream_loop(shutdown, opus_rx, &address, reconnect, || {
+      WebSocketSink::connect(request.clone(), destination, keepalive, transport)
+    }),
+    Request::IceCast(request) => stream_loop(shutdown, opus_rx, &address, reconnect, || {
+      IcecastSink::connect(&request, destination, keepalive, transport)
+    }),
+  }
+}
+
+fn websocket_request(destination: &Destination) -> Result<ClientRequestBuilder, String> {
+  let scheme = if destination.tls { "wss" } else { "ws" };
+  let uri = Uri::builder()
     .scheme(scheme)
-    .authority(format!("{}:{}", url.0, url.1))
-    .path_and_query("/")
-    .build() {
-      Ok(url) => url,
-      Err(e) => {return Err(e.to_string())}
-  };
-
-  let request = ClientRequestBuilder::new(uri.clone())
-    .with_header("password", credentials.get_password())
-    .with_header("username", credentials.get_username());
-
-  let mut last_log = Instant::now();
-
-  loop {
-    if shutdown.load(Ordering::SeqCst) { break; }
-    if !connected.load(Ordering::SeqCst) {
-      match connect(request.clone()) {
-        Ok((mut ws, _)) => {
-          connected.store(true, Ordering::SeqCst);
-          let connected_inner = connected.clone();
-          let opus_rx_receiver = opus_rx.clone();
-          let shutdown_clone = shutdown.clone();
-          spawn(move || {
-            handle_websocket(shutdown_clone, &mut ws, &opus_rx_receiver);
-            connected_inner.store(false, Ordering::SeqCst);
-          });
-        }
-        Err(e) => {
-          if last_log.elapsed() > LOG_TIME {
-            eprintln!("HandshakeError: {e}");
-            last_log = Instant::now();
-          }
-          sleep(std::time::Duration::from_millis(50));
+    .authority(destination.address())
+    .path_and_query(destination.path_and_query())
+    .build()
+    .map_err(|e| e.to_string())?;
+
+  Ok(destination.request_headers().into_iter().fold(
+    ClientRequestBuilder::new(uri),
+    |request, (name, value)| request.with_header(name, value),
+  ))
+}
+
+/// A WebSocket connection to tau-tower, pinging the server to notice when it stops responding.
+struct WebSocketSink {
+  ws: WebSocket<MaybeTlsStream<TcpStream>>,
+  liveness: Liveness,
+}
+
+impl WebSocketSink {
+  /// Connects with TCP keepalive and a write timeout set, then shortens the read
+  /// timeout so that reading frames does not hold up the stream.
+  fn connect(
+    request: ClientRequestBuilder,
+    destination: &Destination,
+    keepalive: &KeepaliveConfig,
+    transport: &Transport,
+  ) -> Result<Self, String> {
+    let stream = transport.connect(destination, keepalive)?;
+    let (ws, _) = client(request, stream).map_err(|e| e.to_string())?;
+    set_read_poll(ws.get_ref()).map_err(|e| e.to_string())?;
+    Ok(Self { ws, liveness: Liveness::new(keepalive) })
+  }
+
+  /// Reads whatever the server has sent, without waiting for more. Pongs, and pings
+  /// from the server, are the usual frames, tungstenite answers the pings on the next send.
+  fn read_frames(&mut self) -> Result<(), String> {
+    loop {
+      match self.ws.read() {
+        Ok(_) => self.liveness.seen(),
+        Err(tungstenite::Error::Io(e)) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+          return Ok(());
         }
+        Err(e) => return Err(e.to_string()),
+      }
+    }
+  }
+}
+
+impl Sink for WebSocketSink {
+  fn send(&mut self, page: &[u8]) -> Result<(), String> {
+    self.ws.send(Message::Binary(page.to_vec().into())).map_err(|e| e.to_string())
+  }
+
+  fn poll(&mut self) -> Result<(), String> {
+    self.read_frames()?;
+    if let Some(silent) = self.liveness.dead() {
+      return Err(format!("no reply from the server for {:.0} s", silent.as_secs_f64()));
+    }
+    if self.liveness.ping_due() {
+      self.ws.send(Message::Ping(Default::default())).map_err(|e| e.to_string())?;
+    }
+    Ok(())
+  }
+
+  /// Sends a Close frame, and waits a moment for the server to answer it.
+  fn close(&mut self) {
+    let frame = CloseFrame { code: CloseCode::Normal, reason: "end of session".into() };
+    if self.ws.close(Some(frame)).is_err() { return; }
+    let until = Instant::now() + CLOSE_TIMEOUT;
+    while Instant::now() < until {
+      match self.ws.read() {
+        Err(tungstenite::Error::Io(e)) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => continue,
+        Err(_) => return,
+        Ok(_) => {}
       }
-    } else {
-      sleep(std::time::Duration::from_millis(50));
     }
   }
-  Ok(())
 }
 
 fn box_err(err: &str) -> Box<dyn std::error::Error + Send + 'static> {
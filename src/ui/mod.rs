@@ -4,26 +4,25 @@ use std::path::Path;
 
 pub fn print_started_session_msg(
   devname: String,
+  channels: usize,
   path: &Path,
   no_rec: bool,
-  ip: &str,
-  port: &u16,
+  destinations: &[String],
 ) {
   println!(
     "\
     \n{style_bold}{color_bright_yellow}Listening to: \
-    \t\t{style_reset}{color_bright_cyan}{}{color_reset} \
+    \t\t{style_reset}{color_bright_cyan}{}{color_reset} ({}) \
     ",
-    devname
+    devname,
+    channel_mode(channels)
   );
 
   println!(
     "\
     {style_bold}{color_bright_yellow}Broadcasting to: \
-    \t{style_reset}{color_bright_cyan}{}:{} \
-    ",
-    ip,
-    port,
+    \t{style_reset}{color_bright_cyan}{}{color_reset}",
+    destinations.join(", "),
   );
   if !no_rec {
     println!(
@@ -38,3 +37,193 @@ pub fn print_started_session_msg(
   }
   println!("Press Ctrl+C to stop.");
 }
+
+pub fn print_routes(devname: &str, inputs: usize, routes: &[crate::audio::routing::Route]) {
+  println!("{color_yellow}{devname}: routing {inputs} device inputs:{color_reset}");
+  for r in routes {
+    println!("\tin {} -> out {} (gain {})", r.input, r.output, r.gain);
+  }
+}
+
+pub fn print_sample_conversion(devname: &str, format: cpal::SampleFormat) {
+  println!(
+    "{color_yellow}{devname}: delivers {format} samples, converting to f32.{color_reset}"
+  );
+}
+
+pub fn print_resampling(devname: &str, from: u32, to: u32) {
+  println!(
+    "{color_yellow}{devname}: runs at {from} Hz, resampling to {to} Hz.{color_reset}"
+  );
+}
+
+pub fn print_now_playing(now: &crate::metadata::NowPlaying) {
+  println!(
+    "{style_bold}{color_bright_yellow}Now playing: \
+    \t\t{style_reset}{color_bright_cyan}{now}{color_reset}"
+  );
+}
+
+pub fn print_connect_failed(address: &str, attempt: u32, err: &str, retry_in: std::time::Duration) {
+  eprintln!(
+    "{color_yellow}Could not connect to {address} (attempt {attempt}):{color_reset} {err}\n\
+    \tRetrying in {:.1} s",
+    retry_in.as_secs_f64()
+  );
+}
+
+pub fn print_connection_lost(address: &str, reason: &str) {
+  eprintln!("{color_yellow}Lost the connection to {address}, reconnecting:{color_reset} {reason}");
+}
+
+pub fn print_dropped(address: &str, attempt: u32, reason: &str, retry_in: std::time::Duration) {
+  eprintln!(
+    "{color_yellow}{address} dropped the connection right away (attempt {attempt}):{color_reset} {reason}\n\
+    \tRetrying in {:.1} s",
+    retry_in.as_secs_f64()
+  );
+}
+
+pub fn print_reconnected(address: &str, attempts: u32, replay_secs: f64) {
+  println!(
+    "{color_bright_yellow}Connected to {address}{color_reset} after {attempts} failed attempt{}.",
+    if attempts == 1 { "" } else { "s" }
+  );
+  if replay_secs > 0.0 {
+    println!("\tReplaying {replay_secs:.1} s of missed audio.");
+  }
+}
+
+pub fn print_giving_up(address: &str, attempts: u32) {
+  eprintln!(
+    "{color_red}{style_bold}Giving up on {address} after {attempts} failed attempts.{style_reset}{color_reset}"
+  );
+}
+
+pub fn print_no_destinations() {
+  eprintln!("{color_red}{style_bold}No server left to stream to, ending the session.{style_reset}{color_reset}");
+}
+
+pub fn print_proxy(address: &str, proxy: &str) {
+  println!("{color_yellow}Connecting to {address} through proxy {proxy}.{color_reset}");
+}
+
+pub fn print_insecure(address: &str) {
+  eprintln!(
+    "{color_red}{style_bold}Not verifying the certificate of {address}, the connection can be intercepted.{style_reset}{color_reset}"
+  );
+}
+
+pub fn print_falling_behind(address: &str) {
+  eprintln!("{color_yellow}{address} can not keep up with the stream, dropping audio for it.{color_reset}");
+}
+
+pub fn print_stopping(no_rec: bool) {
+  println!(
+    "\n{color_yellow}Stopping, {}.{color_reset}",
+    if no_rec { "sending the end of the stream" } else { "sending the end of the stream and finishing the recording" }
+  );
+}
+
+pub fn print_session_summary(duration: std::time::Duration, stats: &[crate::threads::sink::StreamStats], recording: Option<&Path>) {
+  let secs = duration.as_secs();
+  println!(
+    "{style_bold}{color_bright_yellow}Session ended after: \
+    \t{style_reset}{color_bright_cyan}{:02}:{:02}:{:02}{color_reset}",
+    secs / 3600, secs / 60 % 60, secs % 60
+  );
+  for s in stats {
+    let outages = match s.outages {
+      0 => String::new(),
+      1 => ", connection lost once".to_string(),
+      n => format!(", connection lost {n} times"),
+    };
+    println!(
+      "{style_bold}{color_bright_yellow}Streamed to {}: \
+      \t{style_reset}{color_bright_cyan}{}{color_reset}{outages}{}",
+      s.address,
+      byte_size(s.sent),
+      if s.gave_up { ", gave up" } else { "" }
+    );
+  }
+  if let Some(path) = recording {
+    match std::fs::metadata(path) {
+      Ok(m) => println!(
+        "{style_bold}{color_bright_yellow}Saved recording to: \
+        \t{style_reset}{color_bright_cyan}{}{color_reset} ({})",
+        path.display(),
+        byte_size(m.len())
+      ),
+      Err(e) => eprintln!("{color_red}Could not find the recording {}: {e}{color_reset}", path.display()),
+    }
+  }
+}
+
+fn byte_size(bytes: u64) -> String {
+  match bytes {
+    b if b >= 1 << 30 => format!("{:.2} GB", b as f64 / (1u64 << 30) as f64),
+    b if b >= 1 << 20 => format!("{:.1} MB", b as f64 / (1u64 << 20) as f64),
+    b if b >= 1 << 10 => format!("{:.0} kB", b as f64 / (1u64 << 10) as f64),
+    b => format!("{b} B"),
+  }
+}
+
+fn channel_mode(channels: usize) -> String {
+  match channels {
+    1 => "mono".to_string(),
+    2 => "stereo".to_string(),
+    n => format!("{n}ch surround"),
+  }
+}
+
+pub fn print_device_report(hosts: &[crate::audio::devices::HostReport], need: crate::audio::devices::ChannelNeed) {
+  for host in hosts {
+    println!(
+      "\n{style_bold}{color_bright_yellow}Host: {style_reset}{color_bright_cyan}{}{color_reset}{}",
+      host.host,
+      if host.is_default { " (default)" } else { "" }
+    );
+    if let Some(e) = &host.error {
+      println!("\t{color_red}{e}{color_reset}");
+      continue;
+    }
+    if host.devices.is_empty() {
+      println!("\tNo input devices found.");
+    }
+    for dev in &host.devices {
+      let status = if dev.compatible && dev.resampled {
+        format!("{color_green}[ready]{color_reset} {color_yellow}(resampled to {} Hz){color_reset}", crate::DEFAULT_SR)
+      } else if dev.compatible {
+        format!("{color_green}[ready]{color_reset}")
+      } else {
+        format!("{color_red}[unsupported]{color_reset}")
+      };
+      // Devices of other hosts can not be selected by index
+      let index = dev.index.map_or_else(|| " - ".to_string(), |i| format!("{i:>2}:"));
+      println!(
+        "  {style_bold}{index}{style_reset} {color_bright_cyan}{}{color_reset}{} {}",
+        dev.name,
+        if dev.is_default { " (default)" } else { "" },
+        status
+      );
+      for cfg in &dev.configs {
+        let rate = if cfg.min_sample_rate == cfg.max_sample_rate {
+          format!("{} Hz", cfg.min_sample_rate)
+        } else {
+          format!("{}-{} Hz", cfg.min_sample_rate, cfg.max_sample_rate)
+        };
+        println!("\t{} ch, {}, {}", cfg.channels, rate, cfg.sample_format);
+      }
+    }
+  }
+  let channels = if need.at_least {
+    format!("at least {} channels, for the routed inputs", need.channels)
+  } else {
+    format!("{} channels, as configured", need.channels)
+  };
+  println!(
+    "\n{color_green}[ready]{color_reset} devices support {channels}. \
+    Samples are converted to f32, rates other than {} Hz are resampled.",
+    crate::DEFAULT_SR
+  );
+}
@@ -6,13 +6,13 @@ use regex::Regex;
 use std::sync::LazyLock;
 
 /// Formats the file name of output file, using current local datetime.
-/// If no filename is given in cli arguments. default = `tau_[datetime].ogg`
-pub fn format_filename(filename: Option<String>) -> Arc<String> {
+/// If no filename is given in cli arguments. default = `tau_[datetime].<extension>`
+pub fn format_filename(filename: Option<String>, extension: &str) -> Arc<String> {
   let now = Local::now().format("%d-%m-%Y_%H_%M_%S").to_string();
   Arc::new(
     filename
-      .map(|f| format!("{f}.ogg"))
-      .unwrap_or_else(|| format!("tau_{}.ogg", now)),
+      .map(|f| format!("{f}.{extension}"))
+      .unwrap_or_else(|| format!("tau_{}.{extension}", now)),
   )
 }
 
@@ -30,9 +30,14 @@ pub fn create_recordings_dir(path: &Path) -> Result<(), std::io::Error> {
 pub mod consts {
   /// Ogg opus fixed samplerate
   pub const DEFAULT_SR: i32 = 48000;
-  /// Ogg opus fixed channel size
-  // TODO: Handle multichannel stream based on user config
+  /// Default channel count, stereo
   pub const DEFAULT_CH: usize = 2;
+  /// Opus channel mapping family 1 supports up to 8 channels (7.1 surround)
+  pub const MAX_CH: usize = 8;
+  /// Highest samplerate of raw PCM on stdin, resampled to [`DEFAULT_SR`]
+  pub const MAX_SR: u32 = 768000;
+  /// Samples per channel in one 20ms opus frame at [`DEFAULT_SR`]
+  pub const FRAME_LEN: usize = 960;
   #[cfg(target_os = "macos")]
   pub const DEFAULT_INPUT: &str = "BlackHole 2ch";
   #[cfg(target_os = "linux")]